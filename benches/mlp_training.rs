@@ -0,0 +1,74 @@
+//! Times the training loop of `test_mlp_training` with the `Value` graph and
+//! with the same loop recorded on a `Tape`. Run with `cargo bench`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use micrograd::{MLP, Module, Tape, Value};
+use rand::{SeedableRng, rngs::StdRng};
+
+const XS: [[f64; 3]; 4] = [
+  [2.0, 3.0, -1.0],
+  [3.0, -1.0, 0.5],
+  [0.5, 1.0, 1.0],
+  [1.0, 1.0, -1.0],
+];
+const YS: [f64; 4] = [1.0, -1.0, -1.0, 1.0];
+const STEPS: usize = 1_000;
+const RUNS: usize = 10;
+
+fn new_mlp() -> MLP {
+  MLP::new(3, &[4, 4, 1], &mut StdRng::from_seed([0u8; 32]))
+}
+
+fn train_value() -> f64 {
+  train(|f| f())
+}
+
+fn train_tape() -> f64 {
+  let mut tape: Tape = Tape::with_capacity(256);
+  train(|f| tape.record(f))
+}
+
+/// Runs the training loop, building each step's loss through `record`.
+fn train(mut record: impl FnMut(&dyn Fn() -> Value) -> Value) -> f64 {
+  let mut mlp = new_mlp();
+  let xs = XS.map(|x| x.map(Value::constant));
+  let mut loss = 0.0;
+  for _ in 0..STEPS {
+    let mut total = record(&|| {
+      let mut total = Value::from(0);
+      for (x, y) in xs.iter().zip(YS) {
+        let e = mlp.call(&x.iter().collect::<Vec<_>>()).remove(0) - y;
+        total += &e * &e;
+      }
+      total
+    });
+
+    mlp.zero_grad();
+    total.backward().unwrap();
+    for mut p in mlp.parameters() {
+      p.set_data(p.data() - 0.1 * p.grad());
+    }
+    loss = total.data();
+  }
+  loss
+}
+
+fn bench(name: &str, f: fn() -> f64) -> Duration {
+  let loss = f();
+  let mut best = Duration::MAX;
+  for _ in 0..RUNS {
+    let start = Instant::now();
+    black_box(f());
+    best = best.min(start.elapsed());
+  }
+  println!("{name:>6}: {best:>12.3?} for {STEPS} steps (loss {loss:.6})");
+  best
+}
+
+fn main() {
+  let value = bench("value", train_value);
+  let tape = bench("tape", train_tape);
+  println!("speedup: {:.1}x", value.as_secs_f64() / tape.as_secs_f64());
+}
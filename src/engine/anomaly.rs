@@ -0,0 +1,451 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use crate::{Scalar, Value};
+
+thread_local! {
+  static DETECTING: Cell<bool> = const { Cell::new(false) };
+  static ANOMALY: RefCell<Option<Anomaly>> = const { RefCell::new(None) };
+}
+
+/// The first non-finite value found by [`detect_anomaly`]. Data and
+/// gradients are converted to `f64`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Anomaly {
+  /// An op returned a NaN or an infinity. It is caught as the op is
+  /// evaluated, before any root exists, so there is no path.
+  Forward {
+    /// The node holding the output. Outside grad mode, or if no input
+    /// requires a gradient, it is a constant rather than an op node.
+    id: u32,
+    op: String,
+    /// The ids of the input nodes.
+    input_ids: Vec<u32>,
+    /// The labels of the input nodes, empty for the unlabelled ones.
+    input_labels: Vec<String>,
+    inputs: Vec<f64>,
+    output: f64,
+  },
+  /// The gradient function of an op returned a NaN or an infinity for one
+  /// of its inputs during a backward pass or [`crate::grad`].
+  Backward {
+    id: u32,
+    op: String,
+    label: String,
+    inputs: Vec<f64>,
+    /// The gradient of the node itself.
+    grad: f64,
+    /// The gradients passed to each of the inputs.
+    input_grads: Vec<f64>,
+    /// The nodes from a root of the backward pass down to this one.
+    path: Vec<String>,
+  },
+  /// The gradient of a node became a NaN or an infinity as it was written,
+  /// without a gradient function returning one: a seed that isn't finite, a
+  /// hook that returned one, or gradients that overflowed as they were
+  /// added up.
+  Gradient {
+    id: u32,
+    op: String,
+    label: String,
+    /// The gradient of the node before the write.
+    before: f64,
+    /// The gradient added to it, or that replaced it.
+    written: f64,
+    /// The gradient of the node after the write.
+    after: f64,
+  },
+}
+
+impl fmt::Display for Anomaly {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Anomaly::Forward {
+        id,
+        op,
+        input_ids,
+        input_labels,
+        inputs,
+        output,
+      } => {
+        let nodes = input_ids
+          .iter()
+          .zip(input_labels)
+          .map(|(id, label)| match label.as_str() {
+            "" => id.to_string(),
+            label => format!("{} ({})", id, label),
+          })
+          .collect::<Vec<_>>();
+        write!(
+          f,
+          "op {} of node {} returned {} for inputs {:?} of nodes [{}]",
+          op,
+          id,
+          output,
+          inputs,
+          nodes.join(", ")
+        )
+      }
+      Anomaly::Backward {
+        op,
+        inputs,
+        grad,
+        input_grads,
+        path,
+        ..
+      } => write!(
+        f,
+        "gradient function of op {} returned {:?} for inputs {:?} and \
+         gradient {} (path: {})",
+        op,
+        input_grads,
+        inputs,
+        grad,
+        path.join(" -> ")
+      ),
+      Anomaly::Gradient {
+        id,
+        op,
+        label,
+        before,
+        written,
+        after,
+      } => write!(
+        f,
+        "gradient of node {}, {}, became {} when {} was written over {}",
+        id,
+        describe_op(op, label),
+        after,
+        written,
+        before
+      ),
+    }
+  }
+}
+
+impl std::error::Error for Anomaly {}
+
+/// Runs `f` while checking the output of every op and every gradient
+/// computed on the current thread, by a backward pass or by
+/// [`crate::grad`], from the seeds to the sums of the gradients. Returns the
+/// first NaN or infinity found, or the result of `f` if there is none.
+///
+/// `f` still runs to completion after an anomaly. The checks slow every op
+/// down, and backward also has to map the graph to report the path, so
+/// this is meant for debugging.
+#[allow(clippy::result_large_err)]
+pub fn detect_anomaly<R>(f: impl FnOnce() -> R) -> Result<R, Anomaly> {
+  let _guard = AnomalyGuard::new();
+  let result = f();
+  match ANOMALY.with(|anomaly| anomaly.take()) {
+    Some(anomaly) => Err(anomaly),
+    None => Ok(result),
+  }
+}
+
+pub(crate) fn is_detecting() -> bool {
+  DETECTING.with(|detecting| detecting.get())
+}
+
+/// Records `anomaly` unless an earlier one was found.
+fn report(anomaly: impl FnOnce() -> Anomaly) {
+  ANOMALY.with(|slot| {
+    let mut slot = slot.borrow_mut();
+    if slot.is_none() {
+      *slot = Some(anomaly());
+    }
+  });
+}
+
+/// Reports the data of `node`, just computed by `op` from `inputs`, if it is
+/// not finite and anomalies are being detected.
+pub(crate) fn check_forward<T: Scalar>(
+  node: &Value<T>,
+  op: &str,
+  inputs: &[&Value<T>],
+) {
+  if is_detecting() && !node.data().is_finite() {
+    report(|| Anomaly::Forward {
+      id: node.id(),
+      op: op.to_string(),
+      input_ids: inputs.iter().map(|v| v.id()).collect(),
+      input_labels: inputs.iter().map(|v| v.label()).collect(),
+      inputs: to_f64(&inputs.iter().map(|v| v.data()).collect::<Vec<_>>()),
+      output: to_f64(&[node.data()])[0],
+    });
+  }
+}
+
+/// Reports `input_grads`, the gradients `node` passes to its inputs for
+/// its gradient `grad`, if one of them is not finite. `parents` maps each
+/// node to the one it is reached from on a shortest path from the root, as
+/// built by [`parents`].
+pub(crate) fn check_backward<T: Scalar>(
+  node: &Value<T>,
+  grad: T,
+  input_grads: &[T],
+  parents: &HashMap<u32, Value<T>>,
+) {
+  if input_grads.iter().all(|g| g.is_finite()) {
+    return;
+  }
+  report(|| {
+    let mut path = vec![describe(node)];
+    let mut id = node.id();
+    while let Some(parent) = parents.get(&id) {
+      path.push(describe(parent));
+      id = parent.id();
+    }
+    path.reverse();
+
+    let inputs = node.prev().iter().map(Value::data).collect::<Vec<_>>();
+    Anomaly::Backward {
+      id: node.id(),
+      op: node.op().unwrap_or_default(),
+      label: node.label(),
+      inputs: to_f64(&inputs),
+      grad: to_f64(&[grad])[0],
+      input_grads: to_f64(input_grads),
+      path,
+    }
+  });
+}
+
+/// Reports the gradient of `node` if it went from `before` to `after`, which
+/// is not finite, when `written` was added to it or replaced it.
+pub(crate) fn check_grad<T: Scalar>(
+  node: &Value<T>,
+  before: T,
+  written: T,
+  after: T,
+) {
+  if after.is_finite() || !is_detecting() {
+    return;
+  }
+  report(|| Anomaly::Gradient {
+    id: node.id(),
+    op: node.op().unwrap_or_default(),
+    label: node.label(),
+    before: to_f64(&[before])[0],
+    written: to_f64(&[written])[0],
+    after: to_f64(&[after])[0],
+  });
+}
+
+/// Maps every node below `roots` to the node it is first reached from in a
+/// breadth-first walk, so that following the map gives a shortest path back
+/// to one of the roots.
+pub(crate) fn parents<T: Scalar>(roots: &[Value<T>]) -> HashMap<u32, Value<T>> {
+  let mut parents = HashMap::new();
+  let mut queue = roots.iter().cloned().collect::<VecDeque<_>>();
+  while let Some(node) = queue.pop_front() {
+    for input in node.prev() {
+      let is_root = roots.contains(&input);
+      if !is_root && !parents.contains_key(&input.id()) {
+        parents.insert(input.id(), node.clone());
+        queue.push_back(input);
+      }
+    }
+  }
+  parents
+}
+
+/// Names a node by its op and, if it has one, its label.
+fn describe<T: Scalar>(node: &Value<T>) -> String {
+  describe_op(&node.op().unwrap_or_default(), &node.label())
+}
+
+fn describe_op(op: &str, label: &str) -> String {
+  let op = if op.is_empty() { "leaf" } else { op };
+  match label {
+    "" => op.to_string(),
+    label => format!("{} ({})", op, label),
+  }
+}
+
+fn to_f64<T: Scalar>(xs: &[T]) -> Vec<f64> {
+  xs.iter().map(|x| x.to_f64().unwrap_or(f64::NAN)).collect()
+}
+
+/// Turns detection on and restores the previous state when dropped, even if
+/// `f` panics, so that nested calls each report their own anomaly.
+struct AnomalyGuard {
+  prev: bool,
+  prev_anomaly: Option<Anomaly>,
+}
+
+impl AnomalyGuard {
+  fn new() -> Self {
+    let prev = DETECTING.with(|cell| cell.replace(true));
+    let prev_anomaly = ANOMALY.with(|cell| cell.take());
+    Self { prev, prev_anomaly }
+  }
+}
+
+impl Drop for AnomalyGuard {
+  fn drop(&mut self) {
+    DETECTING.with(|cell| cell.set(self.prev));
+    ANOMALY.with(|cell| *cell.borrow_mut() = self.prev_anomaly.take());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_forward_anomaly() {
+    let x = Value::new(0.0, Some("x"));
+
+    let mut y: Value = &x * 2;
+    y.set_label("y");
+
+    let mut ln = None;
+    let err = detect_anomaly(|| {
+      let z = y.ln();
+      ln = Some(z.id());
+      z.exp().sqrt()
+    })
+    .unwrap_err();
+
+    let id = ln.unwrap();
+    assert_eq!(
+      err,
+      Anomaly::Forward {
+        id,
+        op: "ln".to_string(),
+        input_ids: vec![y.id()],
+        input_labels: vec!["y".to_string()],
+        inputs: vec![0.0],
+        output: f64::NEG_INFINITY,
+      }
+    );
+    let message = format!(
+      "op ln of node {} returned -inf for inputs [0.0] of nodes [{} (y)]",
+      id,
+      y.id()
+    );
+    assert_eq!(err.to_string(), message);
+
+    // Over constants the output is a constant, which still has an id.
+    let mut constant = None;
+    let err = detect_anomaly(|| {
+      let z: Value = Value::constant(-1.0).sqrt();
+      constant = Some(z.id());
+    })
+    .unwrap_err();
+    assert!(matches!(err, Anomaly::Forward { id, .. } if Some(id) == constant));
+  }
+
+  #[test]
+  fn test_backward_anomaly() {
+    let x = Value::new(0.0, Some("x"));
+    let y = Value::new(3.0, Some("y"));
+    let mut s = x.sqrt();
+    s.set_label("s");
+
+    let loss = (&s * &y).tanh();
+    let err = detect_anomaly(|| crate::grad(&loss, &[x.clone()])).unwrap_err();
+    assert!(matches!(err, Anomaly::Backward { id, .. } if id == s.id()));
+
+    let err = detect_anomaly(|| {
+      let mut loss = (&s * &y).tanh();
+      loss.backward().unwrap();
+    })
+    .unwrap_err();
+
+    let path = ["tanh", "*", "sqrt (s)"].map(String::from).to_vec();
+    assert_eq!(
+      err,
+      Anomaly::Backward {
+        id: s.id(),
+        op: "sqrt".to_string(),
+        label: "s".to_string(),
+        inputs: vec![0.0],
+        grad: 3.0,
+        input_grads: vec![f64::INFINITY],
+        path,
+      }
+    );
+    assert!(err.to_string().ends_with("(path: tanh -> * -> sqrt (s))"));
+  }
+
+  #[test]
+  fn test_gradient_anomaly() {
+    let gradient = |err: Anomaly| match err {
+      Anomaly::Gradient {
+        id, before, after, ..
+      } => (id, before, after),
+      err => panic!("unexpected anomaly {:?}", err),
+    };
+
+    // A seed that isn't finite.
+    let x = Value::new(2.0, Some("x"));
+    let mut y: Value = x.exp();
+    let err = detect_anomaly(|| y.backward_with(f64::NAN)).unwrap_err();
+    let (id, before, after) = gradient(err);
+    assert_eq!((id, before), (y.id(), 0.0));
+    assert!(after.is_nan());
+
+    // A hook that returns one.
+    let x = Value::new(2.0, Some("x"));
+    let mut y: Value = x.exp();
+    y.register_hook(|_| f64::INFINITY);
+    let err = detect_anomaly(|| y.backward()).unwrap_err();
+    assert_eq!(gradient(err), (y.id(), 1.0, f64::INFINITY));
+
+    // Finite gradients that overflow once they are added up.
+    let overflow = |x: &Value| x * f64::MAX + x * f64::MAX;
+    let x = Value::new(1.0, Some("x"));
+    let mut z = overflow(&x);
+    let err = detect_anomaly(|| z.backward()).unwrap_err();
+    let message = format!("gradient of node {}, leaf (x), became inf", x.id());
+    assert!(err.to_string().starts_with(&message));
+    assert_eq!(gradient(err), (x.id(), f64::MAX, f64::INFINITY));
+
+    let x = Value::new(1.0, Some("x"));
+    let z = overflow(&x);
+    let err = detect_anomaly(|| crate::grad(&z, &[x.clone()])).unwrap_err();
+    assert_eq!(gradient(err), (x.id(), f64::MAX, f64::INFINITY));
+    assert_eq!(x.grad(), 0.0);
+
+    let x = Value::placeholder("x");
+    let mut graph = crate::CompiledGraph::new(&[x.clone()], &[overflow(&x)]);
+    graph.run(&[1.0]).unwrap();
+    let err = detect_anomaly(|| graph.backward()).unwrap_err();
+    assert_eq!(gradient(err), (x.id(), f64::MAX, f64::INFINITY));
+  }
+
+  #[test]
+  fn test_no_anomaly() {
+    let x = Value::new(2.0, Some("x"));
+    let grad = detect_anomaly(|| {
+      let mut y = (&x * &x).tanh();
+      y.backward().unwrap();
+      x.grad()
+    });
+    assert_eq!(grad, Ok(2.0 * 2.0 * (1.0 - 4.0f64.tanh().powi(2))));
+    assert!(!is_detecting());
+
+    // Without detection non-finite values go through unnoticed.
+    let y: Value = Value::new(0.0, None).ln();
+    assert_eq!(y.data(), f64::NEG_INFINITY);
+  }
+
+  #[test]
+  fn test_nested_and_panic() {
+    let x = Value::new(0.0, Some("x"));
+    let outer = detect_anomaly(|| {
+      let inner = detect_anomaly(|| x.ln());
+      assert!(inner.is_err());
+      assert!(is_detecting());
+      x.exp()
+    });
+    assert!(outer.is_ok());
+
+    let result = std::panic::catch_unwind(|| detect_anomaly(|| panic!("boom")));
+    assert!(result.is_err());
+    assert!(!is_detecting());
+  }
+}
@@ -0,0 +1,381 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{Scalar, Value};
+
+use super::anomaly;
+use super::backprop::{build_topo, build_topo_from, check_not_released};
+use super::tape;
+
+/// Computes the gradients of `output` with respect to each of `wrt` without
+/// touching the `grad` field of any node. Only the part of the graph that
+/// lies between `output` and `wrt` is visited.
+///
+/// Inputs that `output` doesn't depend on get a zero gradient. Hooks run on
+/// the gradients of this call as they would during [`Value::backward`].
+///
+/// # Panics
+///
+/// Panics if part of the graph was released by [`Value::backward`], or if
+/// one of `wrt` was recorded on another [`crate::Tape`] than `output`.
+pub fn grad<T: Scalar>(output: &Value<T>, wrt: &[Value<T>]) -> Vec<T> {
+  grad_impl(output, wrt, true)
+}
+
+/// Like [`grad`]. Without `leaf_hooks` the hooks of the leaves don't run, so
+/// the caller can run them on the gradients summed over several calls.
+#[allow(clippy::mutable_key_type)]
+pub(crate) fn grad_impl<T: Scalar>(
+  output: &Value<T>,
+  wrt: &[Value<T>],
+  leaf_hooks: bool,
+) -> Vec<T> {
+  if output.is_recorded() {
+    return tape::grad(output, wrt, leaf_hooks);
+  }
+  grad_from(&[(output.clone(), T::one())], wrt, leaf_hooks)
+}
+
+/// Like [`grad_impl`], for the sum of `roots` weighted by their seeds.
+#[allow(clippy::mutable_key_type)]
+pub(crate) fn grad_from<T: Scalar>(
+  roots: &[(Value<T>, T)],
+  wrt: &[Value<T>],
+  leaf_hooks: bool,
+) -> Vec<T> {
+  let outputs = roots.iter().map(|(v, _)| v.clone()).collect::<Vec<_>>();
+  let topo = build_topo_from(&outputs);
+  if let Err(err) = check_not_released(&topo) {
+    panic!("{}", err);
+  }
+  let reaching = reaching(&topo, wrt);
+  let parents = anomaly::is_detecting().then(|| anomaly::parents(&outputs));
+
+  let mut grads = HashMap::new();
+  for (root, seed) in roots {
+    let acc = grads.entry(root.clone()).or_insert(T::zero());
+    *acc = *acc + *seed;
+  }
+
+  for node in topo.iter().rev() {
+    if !reaching.contains(node) {
+      continue;
+    }
+    let Some(grad) = grads.get_mut(node) else {
+      continue;
+    };
+    if leaf_hooks || !node.prev().is_empty() {
+      let before = *grad;
+      *grad = node.apply_hooks(before);
+      anomaly::check_grad(node, before, *grad, *grad);
+    }
+
+    let input_grads = node.input_grads(*grad);
+    if let Some(parents) = &parents {
+      anomaly::check_backward(node, *grad, &input_grads, parents);
+    }
+    for (input, input_grad) in node.prev().into_iter().zip(input_grads) {
+      if reaching.contains(&input) {
+        let acc = grads.entry(input.clone()).or_insert(T::zero());
+        let before = *acc;
+        *acc = before + input_grad;
+        anomaly::check_grad(&input, before, input_grad, *acc);
+      }
+    }
+  }
+
+  wrt
+    .iter()
+    .map(|v| grads.get(v).copied().unwrap_or(T::zero()))
+    .collect()
+}
+
+/// Computes the gradients of `output` with respect to each of `wrt` as
+/// `Value`s. Unlike [`Value::backward`] the gradients are themselves part of
+/// a graph, so they can be differentiated again for Hessian-vector products,
+/// gradient penalties or Newton steps.
+///
+/// Inputs that `output` doesn't depend on get a constant zero gradient.
+/// Hooks don't run, since they rewrite numbers rather than graphs.
+///
+/// # Panics
+///
+/// Panics if part of the graph was released by [`Value::backward`].
+#[allow(clippy::mutable_key_type)]
+pub fn grad_graph<T: Scalar>(
+  output: &Value<T>,
+  wrt: &[Value<T>],
+) -> Vec<Value<T>> {
+  let topo = build_topo(output);
+  if let Err(err) = check_not_released(&topo) {
+    panic!("{}", err);
+  }
+  let reaching = reaching(&topo, wrt);
+
+  let mut grads = HashMap::new();
+  grads.insert(output.clone(), Value::constant(T::one()));
+
+  for node in topo.iter().rev() {
+    if !reaching.contains(node) || node.prev().is_empty() {
+      continue;
+    }
+    let Some(grad) = grads.get(node).cloned() else {
+      continue;
+    };
+
+    let input_grads = node.invoke_grad_graph_fn(&grad);
+    for (input, input_grad) in node.prev().into_iter().zip(input_grads) {
+      if !reaching.contains(&input) {
+        continue;
+      }
+      let input_grad = match grads.remove(&input) {
+        Some(acc) => acc + input_grad,
+        None => input_grad,
+      };
+      grads.insert(input, input_grad);
+    }
+  }
+
+  wrt
+    .iter()
+    .map(|v| {
+      grads
+        .get(v)
+        .cloned()
+        .unwrap_or_else(|| Value::constant(T::zero()))
+    })
+    .collect()
+}
+
+/// Evaluates `f` at `inputs` and returns its Jacobian, one row per output
+/// and one column per input. The graph is built once and every row is read
+/// off it with [`grad`].
+pub fn jacobian<T, F>(f: F, inputs: &[T]) -> Vec<Vec<T>>
+where
+  T: Scalar,
+  F: Fn(&[Value<T>]) -> Vec<Value<T>>,
+{
+  let inputs = leaves(inputs);
+  f(&inputs)
+    .iter()
+    .map(|output| grad(output, &inputs))
+    .collect()
+}
+
+/// Evaluates the scalar function `f` at `inputs` and returns its Hessian. The
+/// gradient is built as a graph with [`grad_graph`] and each of its entries
+/// is differentiated once more.
+pub fn hessian<T, F>(f: F, inputs: &[T]) -> Vec<Vec<T>>
+where
+  T: Scalar,
+  F: Fn(&[Value<T>]) -> Value<T>,
+{
+  let inputs = leaves(inputs);
+  let output = f(&inputs);
+  grad_graph(&output, &inputs)
+    .iter()
+    .map(|g| grad(g, &inputs))
+    .collect()
+}
+
+/// Creates one labelled leaf per entry of `data`.
+pub(crate) fn leaves<T: Scalar>(data: &[T]) -> Vec<Value<T>> {
+  data
+    .iter()
+    .enumerate()
+    .map(|(i, &v)| Value::new(v, Some(&format!("x{}", i))))
+    .collect()
+}
+
+/// Returns the nodes of `topo` from which at least one of `wrt` can be
+/// reached, including `wrt` themselves. Gradients only need to flow through
+/// these.
+#[allow(clippy::mutable_key_type)]
+fn reaching<T: Scalar>(
+  topo: &[Value<T>],
+  wrt: &[Value<T>],
+) -> HashSet<Value<T>> {
+  let mut reaching = wrt.iter().cloned().collect::<HashSet<_>>();
+  for node in topo {
+    if node.prev().iter().any(|v| reaching.contains(v)) {
+      reaching.insert(node.clone());
+    }
+  }
+  reaching
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_grad() {
+    let x = Value::new(-4.0, Some("x"));
+    let z: Value = 2.0 * &x + 2.0 + &x;
+    let q = z.relu() + &z * &x;
+    let h = (&z * &z).relu();
+    let y = &h + &q + &q * &x;
+
+    let grads = grad(&y, &[x.clone(), z.clone()]);
+    assert_eq!(grads, vec![46.0, -8.0]);
+
+    assert_eq!(x.grad(), 0.0);
+    assert_eq!(z.grad(), 0.0);
+    assert_eq!(y.grad(), 0.0);
+  }
+
+  #[test]
+  fn test_grad_keeps_existing_grads() {
+    let x = Value::new(3.0, Some("x"));
+    let mut y = Value::new(-2.0, Some("y"));
+    let mut out = &x * &y;
+    out.backward_retain_graph().unwrap();
+    assert_eq!(x.grad(), -2.0);
+
+    y.set_grad(10.0);
+    assert_eq!(grad(&out, &[x.clone()]), vec![-2.0]);
+
+    assert_eq!(x.grad(), -2.0);
+    assert_eq!(y.grad(), 10.0);
+    assert_eq!(out.grad(), 1.0);
+  }
+
+  #[test]
+  #[should_panic(expected = "released by an earlier backward")]
+  fn test_grad_released_graph() {
+    let x = Value::new(3.0, Some("x"));
+    let mut y = &x * &x;
+    y.backward().unwrap();
+
+    grad(&y, &[x]);
+  }
+
+  #[test]
+  fn test_grad_skips_unreachable_subgraphs() {
+    let x = Value::new(2.0, Some("x"));
+    let w = Value::new(3.0, Some("w"));
+    let mut calls = Value::new(1.0, Some("calls"));
+    let side = (&w * &w).tanh();
+    calls.set_grad_fn(Box::new(|_, _, _| panic!("visited unrelated node")));
+    let y = &x * &side + &calls;
+
+    assert_eq!(grad(&y, &[x.clone()]), vec![side.data()]);
+    assert_eq!(grad(&y, &[Value::new(0.0, None)]), vec![0.0]);
+  }
+
+  #[test]
+  fn test_jacobian() {
+    // f(x, y) = (x y, x / y, tanh(x))
+    let j = jacobian(
+      |x| vec![&x[0] * &x[1], &x[0] / &x[1], x[0].tanh()],
+      &[3.0, 2.0],
+    );
+
+    let t = 3.0f64.tanh();
+    assert_eq!(
+      j,
+      vec![vec![2.0, 3.0], vec![0.5, -0.75], vec![1.0 - t * t, 0.0],]
+    );
+  }
+
+  #[test]
+  fn test_jacobian_mlp_matches_backward() {
+    use crate::{MLP, Module};
+    use rand::{SeedableRng, rngs::StdRng};
+
+    let mut rng = StdRng::from_seed([0u8; 32]);
+    let mut mlp = MLP::new(3, &[4, 2], &mut rng);
+    let x = [2.0, 3.0, -1.0];
+
+    let j = jacobian(|inputs| mlp.call(&inputs.iter().collect::<Vec<_>>()), &x);
+
+    for (i, row) in j.iter().enumerate() {
+      let inputs = x.iter().map(|&v| Value::new(v, None)).collect::<Vec<_>>();
+      let mut output = mlp.call(&inputs.iter().collect::<Vec<_>>()).remove(i);
+      mlp.zero_grad();
+      output.backward().unwrap();
+      assert_eq!(row, &inputs.iter().map(|v| v.grad()).collect::<Vec<_>>());
+    }
+  }
+
+  #[test]
+  fn test_hessian() {
+    // f(x, y) = x^2 y + y / x
+    let h = hessian(|x| &x[0] * &x[0] * &x[1] + &x[1] / &x[0], &[2.0, 3.0]);
+
+    assert_eq!(h, vec![vec![6.75, 3.75], vec![3.75, 0.0]]);
+  }
+
+  #[test]
+  fn test_hessian_at_minimum() {
+    // f(x, y) = tanh(x)^2 + (y - 1)^2 has its minimum at (0, 1)
+    let h = hessian(
+      |x| {
+        let t = x[0].tanh();
+        let d = &x[1] - 1.0;
+        &t * &t + &d * &d
+      },
+      &[0.0, 1.0],
+    );
+
+    assert_eq!(h, vec![vec![2.0, 0.0], vec![0.0, 2.0]]);
+  }
+
+  #[test]
+  fn test_grad_graph_second_derivative() {
+    let x = Value::new(2.0, Some("x"));
+    let y = &x * &x * &x;
+
+    let dy = grad_graph(&y, &[x.clone()]).remove(0);
+    assert_eq!(dy.data(), 12.0);
+
+    let d2y = grad_graph(&dy, &[x.clone()]).remove(0);
+    assert_eq!(d2y.data(), 12.0);
+
+    let d3y = grad_graph(&d2y, &[x.clone()]).remove(0);
+    assert_eq!(d3y.data(), 6.0);
+  }
+
+  #[test]
+  fn test_grad_graph_backward() {
+    let x = Value::new(0.5, Some("x"));
+    let y = x.tanh();
+
+    let mut dy = grad_graph(&y, &[x.clone()]).remove(0);
+    let t = 0.5f64.tanh();
+    assert!((dy.data() - (1.0 - t * t)).abs() < 1e-12);
+
+    dy.backward().unwrap();
+    assert!((x.grad() - (-2.0 * t * (1.0 - t * t))).abs() < 1e-12);
+  }
+
+  #[test]
+  fn test_grad_graph_hessian_vector_product() {
+    // f(x, y) = x^2 y + y / x, H = [[2y + 2y/x^3, 2x - 1/x^2],
+    //                                [2x - 1/x^2, 0]]
+    let x = Value::new(2.0, Some("x"));
+    let y = Value::new(3.0, Some("y"));
+    let f = &x * &x * &y + &y / &x;
+
+    let grads = grad_graph(&f, &[x.clone(), y.clone()]);
+    assert_eq!(grads[0].data(), 11.25);
+    assert_eq!(grads[1].data(), 4.5);
+
+    let v = [1.0, -1.0];
+    let gv = &grads[0] * v[0] + &grads[1] * v[1];
+    let hv = grad_graph(&gv, &[x.clone(), y.clone()]);
+
+    assert_eq!(hv[0].data(), 6.75 - 3.75);
+    assert_eq!(hv[1].data(), 3.75);
+  }
+
+  #[test]
+  fn test_grad_graph_unrelated_input() {
+    let x = Value::new(2.0, Some("x"));
+    let z = Value::new(5.0, Some("z"));
+    let y = x.relu() - &x;
+
+    let grads = grad_graph(&y, &[x, z]);
+    assert_eq!(grads[0].data(), 0.0);
+    assert_eq!(grads[1].data(), 0.0);
+  }
+}
@@ -1,37 +1,340 @@
 use std::collections::HashSet;
+use std::fmt;
 
-use crate::Value;
+use crate::{Scalar, Value};
 
-impl Value {
-  #[allow(clippy::mutable_key_type)]
-  pub fn backward(&mut self) {
-    let mut topo = vec![];
-    let mut visited = HashSet::new();
+use super::{anomaly, tape};
 
-    build_topo(self, &mut topo, &mut visited);
-    topo.reverse();
+/// Error returned when gradients can't be propagated through a graph.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackwardError {
+  /// A node between the root and the leaves had its graph freed by an earlier
+  /// call to [`Value::backward`].
+  GraphReleased { id: u32, op: Option<String> },
+}
+
+impl fmt::Display for BackwardError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      BackwardError::GraphReleased { id, op } => write!(
+        f,
+        "node {} ({}) was released by an earlier backward; \
+         use backward_retain_graph to backpropagate more than once",
+        id,
+        op.as_deref().unwrap_or("leaf")
+      ),
+    }
+  }
+}
+
+impl std::error::Error for BackwardError {}
+
+impl<T: Scalar> Value<T> {
+  /// Backpropagates from this node and then releases the graph: every
+  /// intermediate node drops its inputs and gradient functions, so the graph
+  /// is freed as soon as the caller lets go of the root.
+  pub fn backward(&mut self) -> Result<(), BackwardError> {
+    self.backward_with(T::one())
+  }
+
+  /// Backpropagates from this node and keeps the graph, so it can be
+  /// backpropagated through again.
+  pub fn backward_retain_graph(&mut self) -> Result<(), BackwardError> {
+    backward_impl(&[(self.clone(), T::one())], true)
+  }
+
+  /// Like [`Value::backward`], seeding this node with the gradient `seed`
+  /// instead of one, e.g. to weight a loss or to compute a vector-Jacobian
+  /// product.
+  pub fn backward_with(&mut self, seed: T) -> Result<(), BackwardError> {
+    backward_impl(&[(self.clone(), seed)], false)
+  }
+}
+
+/// Backpropagates from several roots at once, seeding each with its
+/// gradient, and then releases the graph as [`Value::backward`] does. This
+/// gives the same gradients as backpropagating from the weighted sum of the
+/// roots, with a single pass over the nodes they share.
+///
+/// Like [`Value::backward`], this sets the gradient of each root to its seed,
+/// discarding what a leaf root accumulated before. A root listed several
+/// times is seeded with the sum of its seeds.
+pub fn backward_many<T: Scalar>(
+  roots: &[(Value<T>, T)],
+) -> Result<(), BackwardError> {
+  backward_impl(roots, false)
+}
+
+fn backward_impl<T: Scalar>(
+  roots: &[(Value<T>, T)],
+  retain_graph: bool,
+) -> Result<(), BackwardError> {
+  // Recorded roots are swept on their tapes, which leaves gradients for the
+  // graph values the tapes used. Those are added like the gradients of any
+  // other input, so unlike the roots they accumulate.
+  let (recorded, roots): (Vec<_>, Vec<_>) = roots
+    .iter()
+    .cloned()
+    .partition(|(root, _)| root.is_recorded());
+  let linked = tape::backward(&recorded);
+
+  let outputs = roots
+    .iter()
+    .chain(&linked)
+    .map(|(root, _)| root.clone())
+    .collect::<Vec<_>>();
+  let topo = build_topo_from(&outputs);
+  check_not_released(&topo)?;
+
+  let stashed = reset_grads(&topo);
 
-    self.set_grad(1.0);
-    for mut v in topo {
-      v.invoke_grad_fn();
+  // The path to a bad gradient has to be mapped before the graph is
+  // released.
+  let parents = anomaly::is_detecting().then(|| anomaly::parents(&outputs));
+
+  // The seeds overwrite the gradients of the roots, which only matters for
+  // a leaf root: it starts from its seed on every pass instead of
+  // accumulating. A root listed more than once gets the sum of its seeds.
+  for (root, _) in &roots {
+    root.clone().zero_grad();
+  }
+  for (root, seed) in roots.iter().chain(&linked) {
+    root.clone().add_grad(*seed);
+  }
+  for mut v in topo.into_iter().rev() {
+    v.propagate_grad(|v, input_grads| {
+      if let Some(parents) = &parents {
+        anomaly::check_backward(v, v.grad(), input_grads, parents);
+      }
+    });
+    if !retain_graph {
+      v.release();
+    }
+  }
+
+  restore_grads(stashed);
+
+  Ok(())
+}
+
+/// Prepares the nodes of `topo` for a backward pass. Leaves accumulate across
+/// passes, but intermediate gradients only make sense for the current one.
+/// Leaves with hooks are set aside so that the hooks only see the gradient
+/// of this pass; [`restore_grads`] adds back what this returns.
+pub(crate) fn reset_grads<'a, T: Scalar>(
+  topo: impl IntoIterator<Item = &'a Value<T>>,
+) -> Vec<(Value<T>, T)> {
+  let mut stashed = vec![];
+  for v in topo {
+    if !v.prev().is_empty() {
+      v.clone().zero_grad();
+    } else if v.has_hooks() {
+      stashed.push((v.clone(), v.grad()));
+      v.clone().zero_grad();
     }
   }
+  stashed
 }
 
+pub(crate) fn restore_grads<T: Scalar>(stashed: Vec<(Value<T>, T)>) {
+  for (mut v, grad) in stashed {
+    v.add_grad(grad);
+  }
+}
+
+pub(crate) fn check_not_released<T: Scalar>(
+  topo: &[Value<T>],
+) -> Result<(), BackwardError> {
+  match topo.iter().find(|v| v.is_released()) {
+    Some(v) => Err(BackwardError::GraphReleased {
+      id: v.id(),
+      op: v.op(),
+    }),
+    None => Ok(()),
+  }
+}
+
+/// Returns every node reachable from `root` in topological order, parents
+/// before children. The walk uses an explicit work stack instead of
+/// recursion so that arbitrarily deep graphs don't overflow the call stack.
+pub(crate) fn build_topo<T: Scalar>(root: &Value<T>) -> Vec<Value<T>> {
+  build_topo_from(std::slice::from_ref(root))
+}
+
+/// Like [`build_topo`], for the nodes reachable from any of `roots`.
 #[allow(clippy::mutable_key_type)]
-fn build_topo(
-  value: &Value,
-  topo: &mut Vec<Value>,
-  visited: &mut HashSet<Value>,
-) {
-  if visited.contains(value) {
-    return;
+pub(crate) fn build_topo_from<T: Scalar>(roots: &[Value<T>]) -> Vec<Value<T>> {
+  let mut topo = vec![];
+  let mut visited = HashSet::new();
+  let mut stack = roots
+    .iter()
+    .rev()
+    .map(|root| (root.clone(), false))
+    .collect::<Vec<_>>();
+
+  while let Some((value, expanded)) = stack.pop() {
+    if expanded {
+      topo.push(value);
+      continue;
+    }
+
+    if !visited.insert(value.clone()) {
+      continue;
+    }
+
+    let prev = value.prev();
+    stack.push((value, true));
+    for parent in prev.into_iter().rev() {
+      if !visited.contains(&parent) {
+        stack.push((parent, false));
+      }
+    }
+  }
+
+  topo
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::engine::shared::Shared;
+
+  #[test]
+  fn test_backward_deep_chain() {
+    let x = Value::new(1.0, Some("x"));
+    let mut y = Value::new(0.0, Some("y"));
+    for _ in 0..1_000_000 {
+      y = &y + &x;
+    }
+
+    y.backward().unwrap();
+
+    assert_eq!(y.data(), 1_000_000.0);
+    assert_eq!(x.grad(), 1_000_000.0);
   }
 
-  visited.insert(value.clone());
-  for parent in value.prev() {
-    build_topo(&parent, topo, visited);
+  #[test]
+  fn test_backward_releases_graph() {
+    let x = Value::new(2.0, Some("x"));
+    let y = Value::new(3.0, Some("y"));
+    let xy = &x * &y;
+    let weak = Shared::downgrade(xy.node());
+    let mut z = xy.tanh();
+    drop(xy);
+
+    z.backward().unwrap();
+
+    assert!(weak.upgrade().is_none());
+    assert!(z.prev().is_empty());
+    assert_eq!(z.op(), Some("tanh".to_string()));
+    assert!(!x.is_released());
+
+    let grad = x.grad();
+    assert_eq!(
+      z.backward(),
+      Err(BackwardError::GraphReleased {
+        id: z.id(),
+        op: Some("tanh".to_string()),
+      })
+    );
+    assert_eq!(x.grad(), grad);
   }
 
-  topo.push(value.clone());
+  #[test]
+  fn test_backward_through_released_node() {
+    let x = Value::new(2.0, Some("x"));
+    let h = &x * &x;
+    let mut y = h.relu();
+    y.backward().unwrap();
+
+    let mut z = &h + &x;
+    let err = z.backward().unwrap_err();
+    assert_eq!(
+      err,
+      BackwardError::GraphReleased {
+        id: h.id(),
+        op: Some("*".to_string()),
+      }
+    );
+    assert!(err.to_string().contains("backward_retain_graph"));
+
+    let mut w: Value = &x * 3;
+    w.backward().unwrap();
+    assert_eq!(x.grad(), 4.0 + 3.0);
+  }
+
+  #[test]
+  fn test_backward_retain_graph() {
+    let x = Value::new(2.0, Some("x"));
+    let mut y = (&x * &x).tanh();
+
+    y.backward_retain_graph().unwrap();
+    let grad = x.grad();
+    y.backward_retain_graph().unwrap();
+    assert_eq!(x.grad(), 2.0 * grad);
+
+    y.backward().unwrap();
+    assert_eq!(x.grad(), 3.0 * grad);
+    assert!(y.backward().is_err());
+  }
+
+  #[test]
+  fn test_backward_with_seed() {
+    let x = Value::new(2.0, Some("x"));
+    let mut y = (&x * &x).tanh();
+    y.backward_with(-0.5).unwrap();
+    assert_eq!(x.grad(), -0.5 * 4.0 * (1.0 - 4.0f64.tanh().powi(2)));
+    assert!(y.is_released());
+  }
+
+  #[test]
+  fn test_backward_many() {
+    let x = Value::new(2.0, Some("x"));
+    let y = Value::new(-1.0, Some("y"));
+    let shared = &x * &y;
+    let a = shared.tanh();
+    let b: Value = &shared * &x + 1;
+
+    let expected = {
+      let (x, y) = (Value::new(2.0, None), Value::new(-1.0, None));
+      let shared = &x * &y;
+      let mut sum: Value = shared.tanh() * 3 + (&shared * &x + 1) * 0.5;
+      sum.backward().unwrap();
+      (x.grad(), y.grad())
+    };
+
+    backward_many(&[(a.clone(), 3.0), (b, 0.5)]).unwrap();
+    assert_eq!((x.grad(), y.grad()), expected);
+    assert!(shared.is_released());
+    assert!(backward_many(&[(a, 1.0)]).is_err());
+  }
+
+  #[test]
+  fn test_leaf_root_is_overwritten() {
+    let mut x = Value::new(3.0, Some("x"));
+    x.backward().unwrap();
+    x.backward().unwrap();
+    assert_eq!(x.grad(), 1.0);
+
+    x.backward_with(2.0).unwrap();
+    assert_eq!(x.grad(), 2.0);
+    backward_many(&[(x.clone(), 0.5), (x.clone(), 0.25)]).unwrap();
+    assert_eq!(x.grad(), 0.75);
+
+    // A leaf root that other roots depend on still gets their gradients.
+    let y: Value = &x * 4;
+    backward_many(&[(y, 1.0), (x.clone(), 1.0)]).unwrap();
+    assert_eq!(x.grad(), 5.0);
+  }
+
+  #[test]
+  fn test_backward_many_nested_roots() {
+    // One root feeding another gets its own seed on top of the gradient
+    // flowing back from the other.
+    let x = Value::new(3.0, Some("x"));
+    let h = &x * &x;
+    let y: Value = &h * 2;
+    backward_many(&[(y, 1.0), (h.clone(), 1.0), (h, 1.0)]).unwrap();
+    assert_eq!(x.grad(), (2.0 + 1.0 + 1.0) * 6.0);
+  }
 }
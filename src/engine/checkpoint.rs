@@ -0,0 +1,321 @@
+use std::collections::HashSet;
+
+use crate::{Scalar, Value, grad_graph, is_grad_enabled, no_grad};
+
+use super::autograd::{grad_impl, leaves};
+use super::backprop::build_topo;
+use super::grad_mode::with_grad;
+use super::op_kind::OpInfo;
+use super::shared::{Lock, Shared};
+
+/// Something computed at a point, given by the data of the segment's inputs
+/// and parameters.
+type AtPoint<T, V> = Lock<Option<(Vec<T>, V)>>;
+
+/// A checkpointed segment, shared by the nodes of its outputs. Both caches
+/// hold a single point, so they don't grow with the number of runs.
+struct Segment<T: Scalar, F> {
+  f: F,
+  /// The number of inputs. The data of the parameters follows theirs.
+  n: usize,
+  params: Vec<Value<T>>,
+  /// The outputs at the point they were last evaluated at.
+  outputs: AtPoint<T, Vec<T>>,
+  /// The rows of the Jacobian at the point of the current backward pass that
+  /// haven't been used yet.
+  jacobian: AtPoint<T, Vec<Option<Vec<T>>>>,
+}
+
+/// Evaluates the segment `f` at `inputs` without keeping its inner graph.
+/// Each output becomes a single node over `inputs` and the parameters `f`
+/// uses, and `backward` recomputes the segment to get its gradients. Memory
+/// then grows with the number of segments instead of the number of ops, at
+/// the cost of evaluating every segment twice.
+///
+/// Recomputing the outputs, during backward or in a
+/// [`crate::CompiledGraph`] run, evaluates the segment once for all of them.
+/// `f` must be deterministic. The parameters are found by tracing `f` once,
+/// so it should capture only leaves; intermediate values belong in `inputs`.
+/// [`crate::CompiledGraph::optimize`] can't tell segments apart, so it never
+/// merges their outputs.
+#[allow(clippy::mutable_key_type)]
+pub fn checkpoint<T, F>(inputs: &[Value<T>], f: F) -> Vec<Value<T>>
+where
+  T: Scalar,
+  F: Fn(&[Value<T>]) -> Vec<Value<T>> + Send + Sync + 'static,
+{
+  if !is_grad_enabled() {
+    return f(inputs);
+  }
+
+  let data = inputs.iter().map(Value::data).collect::<Vec<_>>();
+  let segment_inputs = leaves(&data);
+  let (out_data, params) = {
+    let outputs = f(&segment_inputs);
+    let segment_inputs = segment_inputs.iter().collect::<HashSet<_>>();
+    let mut seen = HashSet::new();
+    let params = outputs
+      .iter()
+      .flat_map(build_topo)
+      .filter(|v| v.prev().is_empty() && v.requires_grad())
+      .filter(|v| !segment_inputs.contains(v) && seen.insert(v.clone()))
+      .collect::<Vec<_>>();
+    (outputs.iter().map(Value::data).collect::<Vec<_>>(), params)
+  };
+
+  let prev = inputs.iter().chain(&params).collect::<Vec<_>>();
+  let point = prev.iter().map(|v| v.data()).collect::<Vec<_>>();
+  let segment = Shared::new(Segment {
+    f,
+    n: inputs.len(),
+    params: params.clone(),
+    outputs: Lock::new(Some((point, out_data.clone()))),
+    jacobian: Lock::new(None),
+  });
+  out_data
+    .into_iter()
+    .enumerate()
+    .map(|(j, out)| {
+      let forward = segment.clone();
+      let backward = segment.clone();
+      let graph = segment.clone();
+      Value::from_op_with_data(
+        out,
+        move |data| forward.output(data, j),
+        OpInfo::opaque(format!("checkpoint[{}]", j)),
+        &prev,
+        move |data, _, grad| {
+          let row = backward.jacobian_row(data, j);
+          row.into_iter().map(|d| grad * d).collect()
+        },
+        move |prev, _, grad| {
+          let outputs = (graph.f)(&prev[..graph.n]);
+          grad_graph(&outputs[j], prev)
+            .into_iter()
+            .map(|d| grad * d)
+            .collect()
+        },
+      )
+    })
+    .collect()
+}
+
+impl<T, F> Segment<T, F>
+where
+  T: Scalar,
+  F: Fn(&[Value<T>]) -> Vec<Value<T>>,
+{
+  /// Output `j` at `data`. The segment is evaluated once for all of its
+  /// outputs, so recomputing them at the same point reuses the result.
+  fn output(&self, data: &[T], j: usize) -> T {
+    if let Some((point, outputs)) = self.outputs.borrow().as_ref() {
+      if point == data {
+        return outputs[j];
+      }
+    }
+    let inputs = data[..self.n].iter().map(|&x| Value::constant(x));
+    let outputs = no_grad(|| (self.f)(&inputs.collect::<Vec<_>>()));
+    let outputs = outputs.iter().map(Value::data).collect::<Vec<_>>();
+    let output = outputs[j];
+    *self.outputs.borrow_mut() = Some((data.to_vec(), outputs));
+    output
+  }
+
+  /// Row `j` of the Jacobian at `data`. The whole Jacobian is computed at
+  /// once and each row is handed out once, so it is dropped as soon as the
+  /// backward pass has reached every output.
+  fn jacobian_row(&self, data: &[T], j: usize) -> Vec<T> {
+    {
+      let mut jacobian = self.jacobian.borrow_mut();
+      if let Some((point, rows)) = jacobian.as_mut() {
+        if point == data && rows[j].is_some() {
+          let row = rows[j].take().unwrap();
+          if rows.iter().all(Option::is_none) {
+            *jacobian = None;
+          }
+          return row;
+        }
+      }
+    }
+    let mut rows = with_grad(|| {
+      let inputs = leaves(&data[..self.n]);
+      let outputs = (self.f)(&inputs);
+      let wrt = [inputs, self.params.clone()].concat();
+      // The hooks of the parameters run once the gradients leave the
+      // segment.
+      let rows = outputs.iter().map(|out| Some(grad_impl(out, &wrt, false)));
+      rows.collect::<Vec<_>>()
+    });
+    let row = rows[j].take().unwrap();
+    let rest = rows.iter().any(Option::is_some);
+    *self.jacobian.borrow_mut() = rest.then(|| (data.to_vec(), rows));
+    row
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  use super::*;
+  use crate::{CompiledGraph, Layer, Module, hessian};
+  use rand::{SeedableRng, rngs::StdRng};
+
+  /// Shared, so that a segment can hold on to its layer.
+  fn new_layers() -> Vec<Shared<Layer>> {
+    let mut rng = StdRng::from_seed([0u8; 32]);
+    let layers = (0..6).map(|_| Layer::new(4, 4, true, &mut rng));
+    layers.map(Shared::new).collect()
+  }
+
+  fn call(layer: &Layer, x: &[Value]) -> Vec<Value> {
+    layer.call(&x.iter().collect::<Vec<_>>())
+  }
+
+  fn loss(y: &[Value]) -> Value {
+    y.iter().map(|y| y * y).sum()
+  }
+
+  #[test]
+  fn test_matches_backward() {
+    let data = [0.5, -1.0, 2.0, 0.25];
+
+    let layers = new_layers();
+    let x = leaves(&data);
+    let mut y = loss(&layers.iter().fold(x.clone(), |x, l| call(l, &x)));
+    y.backward().unwrap();
+    let params = layers.iter().flat_map(|l| l.parameters());
+    let expected = params.chain(x).map(|v| v.grad()).collect::<Vec<_>>();
+
+    let layers = new_layers();
+    let x = leaves(&data);
+    let mut y_ckpt = loss(&layers.iter().fold(x.clone(), |x, l| {
+      let l = l.clone();
+      checkpoint(&x, move |x| call(&l, x))
+    }));
+    assert_eq!(y_ckpt.data(), y.data());
+    y_ckpt.backward().unwrap();
+    let params = layers.iter().flat_map(|l| l.parameters());
+    let grads = params.chain(x).map(|v| v.grad()).collect::<Vec<_>>();
+
+    for (g, e) in grads.into_iter().zip(expected) {
+      assert!((g - e).abs() < 1e-12, "{} != {}", g, e);
+    }
+  }
+
+  #[test]
+  fn test_keeps_only_inputs_and_parameters() {
+    let layer = new_layers().remove(0);
+    let x = leaves(&[0.5, -1.0, 2.0, 0.25]);
+    let l = layer.clone();
+    let y = checkpoint(&x, move |x| call(&l, x));
+
+    let ids = |v: &[Value]| v.iter().map(Value::id).collect::<HashSet<_>>();
+    for (j, y) in y.iter().enumerate() {
+      let prev = y.prev();
+      assert_eq!(y.op(), Some(format!("checkpoint[{}]", j)));
+      assert_eq!(prev[..4], x);
+      assert_eq!(ids(&prev[4..]), ids(&layer.parameters()));
+    }
+  }
+
+  #[test]
+  fn test_recomputes_once_per_backward() {
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    let x = leaves(&[1.5, -0.5]);
+    let y = checkpoint(&x, |x| {
+      CALLS.fetch_add(1, Ordering::SeqCst);
+      vec![&x[0] * &x[1], x[0].exp(), x[1].tanh()]
+    });
+    assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+
+    let mut total = Value::sum(&y);
+    total.backward().unwrap();
+    assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+    assert_eq!(x[0].grad(), -0.5 + 1.5f64.exp());
+    assert_eq!(x[1].grad(), 1.5 + 1.0 - 0.5f64.tanh().powi(2));
+  }
+
+  #[test]
+  fn test_runs_segment_once() {
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    let x = Value::placeholder("x");
+    let y = checkpoint(&[x.clone()], |x| {
+      CALLS.fetch_add(1, Ordering::SeqCst);
+      vec![x[0].exp(), x[0].sin(), x[0].cos()]
+    });
+    let mut graph = CompiledGraph::new(&[x.clone()], &y);
+    assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+
+    let outputs = graph.run(&[0.5]).unwrap();
+    assert_eq!(outputs, [0.5f64.exp(), 0.5f64.sin(), 0.5f64.cos()]);
+    assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+    graph.run(&[0.5]).unwrap();
+    assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+
+    // Each backward pass uses up the Jacobian it computes.
+    let mut total = Value::sum(&y);
+    total.backward_retain_graph().unwrap();
+    assert_eq!(CALLS.load(Ordering::SeqCst), 3);
+    total.backward_retain_graph().unwrap();
+    assert_eq!(CALLS.load(Ordering::SeqCst), 4);
+  }
+
+  #[test]
+  fn test_runs_parameter_hooks_once() {
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    let hooked = |data: f64| {
+      let w = Value::new(data, Some("w"));
+      w.register_hook(|g: f64| {
+        CALLS.fetch_add(1, Ordering::SeqCst);
+        g * 2.0
+      });
+      w
+    };
+    let x = leaves(&[3.0]);
+
+    let w = hooked(0.5);
+    let mut y = &x[0] * &w;
+    y.backward().unwrap();
+    let expected = w.grad();
+    assert_eq!(CALLS.swap(0, Ordering::SeqCst), 1);
+
+    let w = hooked(0.5);
+    let w_segment = w.clone();
+    let mut y = checkpoint(&x, move |v| vec![&v[0] * &w_segment]).remove(0);
+    y.backward().unwrap();
+    assert_eq!(w.grad(), expected);
+    assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+  }
+
+  #[test]
+  fn test_higher_order() {
+    let f = |x: &[Value]| vec![&x[0] * x[1].exp() + x[0].sin()];
+    let plain = hessian(|x| f(x).remove(0), &[0.3, 0.7]);
+    let ckpt = hessian(|x| checkpoint(x, f).remove(0), &[0.3, 0.7]);
+    for (a, b) in plain.iter().flatten().zip(ckpt.iter().flatten()) {
+      assert!((a - b).abs() < 1e-12);
+    }
+  }
+
+  #[test]
+  fn test_optimize_keeps_segments_apart() {
+    let x = Value::placeholder("x");
+    let exp = checkpoint(&[x.clone()], |v| vec![v[0].exp()]).remove(0);
+    let sin = checkpoint(&[x.clone()], |v| vec![v[0].sin()]).remove(0);
+    let mut graph = CompiledGraph::new(&[x.clone()], &[exp + sin]);
+    let mut optimized = graph.optimize();
+
+    let expected = 0.5f64.exp() + 0.5f64.sin();
+    assert_eq!(graph.run(&[0.5]), Ok(vec![expected]));
+    assert_eq!(optimized.run(&[0.5]), Ok(vec![expected]));
+  }
+
+  #[test]
+  fn test_no_grad() {
+    let x = leaves(&[2.0]);
+    let y = no_grad(|| checkpoint(&x, |x| vec![&x[0] * &x[0]]));
+    assert_eq!(y[0].data(), 4.0);
+    assert!(!y[0].requires_grad());
+  }
+}
@@ -0,0 +1,163 @@
+use crate::{Scalar, Value};
+
+use super::op_kind::OpInfo;
+use super::shared::Shared;
+
+/// A user-defined scalar function, applied with [`Value::apply`].
+///
+/// `forward` maps the input data to the output, and `backward` maps the
+/// inputs, the output and the upstream gradient to one gradient per input.
+/// Ops that also implement `backward_graph`, returning the same gradients
+/// built from differentiable ops, can be used with [`crate::grad_graph`] and
+/// [`crate::hessian`].
+///
+/// `name` is only the op label: [`crate::CompiledGraph::optimize`] can't
+/// tell what a custom op computes, so it never merges its nodes.
+pub trait CustomOp<T: Scalar = f64>: Send + Sync + 'static {
+  fn name(&self) -> String;
+
+  fn forward(&self, inputs: &[T]) -> T;
+
+  fn backward(&self, inputs: &[T], out: T, grad: T) -> Vec<T>;
+
+  fn backward_graph(
+    &self,
+    _prev: &[Value<T>],
+    _out: &Value<T>,
+    _grad: &Value<T>,
+  ) -> Option<Vec<Value<T>>> {
+    None
+  }
+}
+
+impl<T: Scalar> Value<T> {
+  /// Applies `op` to `inputs`, recording it in the graph under `op.name()`.
+  pub fn apply<O: CustomOp<T>>(op: O, inputs: &[&Value<T>]) -> Value<T> {
+    let name = op.name();
+    let op = Shared::new(op);
+    let forward_op = op.clone();
+    let graph_op = op.clone();
+    let graph_name = name.clone();
+    Value::from_op(
+      move |inputs| forward_op.forward(inputs),
+      OpInfo::opaque(name),
+      inputs,
+      move |inputs, out, grad| {
+        let grads = op.backward(inputs, out, grad);
+        assert_eq!(
+          grads.len(),
+          inputs.len(),
+          "op {:?} returned the wrong number of gradients",
+          op.name()
+        );
+        grads
+      },
+      move |prev, out, grad| {
+        let Some(grads) = graph_op.backward_graph(prev, out, grad) else {
+          panic!(
+            "op {:?} does not support gradients with a graph",
+            graph_name
+          );
+        };
+        grads
+      },
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::engine::gradcheck::assert_gradients;
+  use crate::{GradCheck, grad_graph, gradcheck};
+
+  /// `sqrt(a² + b²)`
+  struct Hypot;
+
+  impl CustomOp for Hypot {
+    fn name(&self) -> String {
+      "hypot".to_string()
+    }
+
+    fn forward(&self, inputs: &[f64]) -> f64 {
+      inputs[0].hypot(inputs[1])
+    }
+
+    fn backward(&self, inputs: &[f64], out: f64, grad: f64) -> Vec<f64> {
+      vec![grad * inputs[0] / out, grad * inputs[1] / out]
+    }
+
+    fn backward_graph(
+      &self,
+      prev: &[Value],
+      out: &Value,
+      grad: &Value,
+    ) -> Option<Vec<Value>> {
+      Some(vec![grad * &prev[0] / out, grad * &prev[1] / out])
+    }
+  }
+
+  /// `x³`, first-order only.
+  struct Cube;
+
+  impl CustomOp for Cube {
+    fn name(&self) -> String {
+      "cube".to_string()
+    }
+
+    fn forward(&self, inputs: &[f64]) -> f64 {
+      inputs[0].powi(3)
+    }
+
+    fn backward(&self, inputs: &[f64], _: f64, grad: f64) -> Vec<f64> {
+      vec![grad * 3.0 * inputs[0] * inputs[0]]
+    }
+  }
+
+  #[test]
+  fn test_apply() {
+    let a = Value::new(3.0, Some("a"));
+    let b = Value::new(4.0, Some("b"));
+    let mut y = Value::apply(Hypot, &[&a, &b]);
+    assert_eq!(y.data(), 5.0);
+    assert_eq!(y.op(), Some("hypot".to_string()));
+    assert_eq!(y.prev(), vec![a.clone(), b.clone()]);
+
+    y.backward().unwrap();
+    assert_eq!(a.grad(), 0.6);
+    assert_eq!(b.grad(), 0.8);
+
+    assert_gradients(|x| Value::apply(Hypot, &[&x[0], &x[1]]), &[1.5, -0.5]);
+    assert_gradients(
+      |x| Value::apply(Hypot, &[&x[0], &x[1]]).tanh() * &x[0],
+      &[0.3, 2.0],
+    );
+  }
+
+  #[test]
+  fn test_apply_first_order_only() {
+    let result: GradCheck = gradcheck(
+      |x| vec![Value::apply(Cube, &[&x[0]]) * &x[1]],
+      &[1.2, -0.7],
+      1e-6,
+      1e-6,
+    );
+    assert!(result.passed(), "{:?}", result);
+  }
+
+  #[test]
+  #[should_panic(expected = "does not support gradients with a graph")]
+  fn test_apply_without_backward_graph() {
+    let x = Value::new(2.0, None);
+    let y = Value::apply(Cube, &[&x]);
+    grad_graph(&y, &[x]);
+  }
+
+  #[test]
+  fn test_apply_constant_inputs() {
+    let x = Value::constant(2.0);
+    let y = Value::apply(Cube, &[&x]);
+    assert_eq!(y.data(), 8.0);
+    assert!(!y.requires_grad());
+  }
+}
@@ -0,0 +1,299 @@
+use std::ops::*;
+
+use crate::Scalar;
+
+/// A dual number `value + tangent·ε` with `ε² = 0`, used for forward-mode
+/// automatic differentiation. Evaluating a function on duals carries the
+/// directional derivative along with the result, so a single pass gives a
+/// Jacobian-vector product without building a graph.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dual<T: Scalar = f64> {
+  value: T,
+  tangent: T,
+}
+
+impl<T: Scalar> Dual<T> {
+  pub fn new(value: T, tangent: T) -> Self {
+    Self { value, tangent }
+  }
+
+  pub fn constant(value: T) -> Self {
+    Self::new(value, T::zero())
+  }
+
+  pub fn value(&self) -> T {
+    self.value
+  }
+
+  pub fn tangent(&self) -> T {
+    self.tangent
+  }
+
+  pub fn relu(&self) -> Dual<T> {
+    if self.value > T::zero() {
+      *self
+    } else {
+      Dual::constant(T::zero())
+    }
+  }
+
+  pub fn tanh(&self) -> Dual<T> {
+    let value = self.value.tanh();
+    Dual::new(value, self.tangent * (T::one() - value * value))
+  }
+}
+
+/// Evaluates `f` at `x` and its Jacobian-vector product along `v` in one
+/// forward pass, returning `(f(x), J·v)`.
+pub fn jvp<T, F>(f: F, x: &[T], v: &[T]) -> (Vec<T>, Vec<T>)
+where
+  T: Scalar,
+  F: Fn(&[Dual<T>]) -> Vec<Dual<T>>,
+{
+  assert_eq!(x.len(), v.len());
+  let inputs = x
+    .iter()
+    .zip(v)
+    .map(|(&x, &v)| Dual::new(x, v))
+    .collect::<Vec<_>>();
+
+  f(&inputs).iter().map(|y| (y.value, y.tangent)).unzip()
+}
+
+macro_rules! impl_dual_op_ref {
+  ($Op:ident, $op:ident, $OpAssign:ident, $op_assign:ident) => {
+    impl<T: Scalar> $Op<&Dual<T>> for Dual<T> {
+      type Output = Dual<T>;
+
+      fn $op(self, rhs: &Dual<T>) -> Self::Output {
+        self.$op(*rhs)
+      }
+    }
+
+    impl<T: Scalar> $Op<Dual<T>> for &Dual<T> {
+      type Output = Dual<T>;
+
+      fn $op(self, rhs: Dual<T>) -> Self::Output {
+        (*self).$op(rhs)
+      }
+    }
+
+    impl<T: Scalar> $Op<&Dual<T>> for &Dual<T> {
+      type Output = Dual<T>;
+
+      fn $op(self, rhs: &Dual<T>) -> Self::Output {
+        (*self).$op(*rhs)
+      }
+    }
+
+    impl<T: Scalar> $OpAssign<Dual<T>> for Dual<T> {
+      fn $op_assign(&mut self, rhs: Dual<T>) {
+        *self = (*self).$op(rhs);
+      }
+    }
+
+    impl<T: Scalar> $OpAssign<&Dual<T>> for Dual<T> {
+      fn $op_assign(&mut self, rhs: &Dual<T>) {
+        *self = (*self).$op(*rhs);
+      }
+    }
+  };
+}
+
+macro_rules! impl_dual_op_num {
+  ($Op:ident, $op:ident, $OpAssign:ident, $op_assign:ident, $ty:ty) => {
+    impl<T: Scalar> $Op<$ty> for Dual<T> {
+      type Output = Dual<T>;
+      fn $op(self, rhs: $ty) -> Self::Output {
+        self.$op(Dual::constant(T::lit(rhs as f64)))
+      }
+    }
+
+    impl<T: Scalar> $Op<$ty> for &Dual<T> {
+      type Output = Dual<T>;
+      fn $op(self, rhs: $ty) -> Self::Output {
+        (*self).$op(rhs)
+      }
+    }
+
+    impl<T: Scalar> $Op<Dual<T>> for $ty {
+      type Output = Dual<T>;
+      fn $op(self, rhs: Dual<T>) -> Self::Output {
+        Dual::constant(T::lit(self as f64)).$op(rhs)
+      }
+    }
+
+    impl<T: Scalar> $Op<&Dual<T>> for $ty {
+      type Output = Dual<T>;
+      fn $op(self, rhs: &Dual<T>) -> Self::Output {
+        self.$op(*rhs)
+      }
+    }
+
+    impl<T: Scalar> $OpAssign<$ty> for Dual<T> {
+      fn $op_assign(&mut self, rhs: $ty) {
+        *self = (*self).$op(rhs);
+      }
+    }
+  };
+}
+
+macro_rules! impl_dual_num {
+  ($ty:ty) => {
+    impl<T: Scalar> From<$ty> for Dual<T> {
+      fn from(value: $ty) -> Self {
+        Dual::constant(T::lit(value as f64))
+      }
+    }
+  };
+}
+
+macro_rules! impl_dual_op {
+  ($Op:ident, $op:ident, $OpAssign:ident, $op_assign:ident) => {
+    impl_dual_op_ref!($Op, $op, $OpAssign, $op_assign);
+    impl_dual_op_num!($Op, $op, $OpAssign, $op_assign, f64);
+    impl_dual_op_num!($Op, $op, $OpAssign, $op_assign, f32);
+    impl_dual_op_num!($Op, $op, $OpAssign, $op_assign, i8);
+    impl_dual_op_num!($Op, $op, $OpAssign, $op_assign, u8);
+    impl_dual_op_num!($Op, $op, $OpAssign, $op_assign, i16);
+    impl_dual_op_num!($Op, $op, $OpAssign, $op_assign, u16);
+    impl_dual_op_num!($Op, $op, $OpAssign, $op_assign, i32);
+    impl_dual_op_num!($Op, $op, $OpAssign, $op_assign, u32);
+    impl_dual_op_num!($Op, $op, $OpAssign, $op_assign, i64);
+    impl_dual_op_num!($Op, $op, $OpAssign, $op_assign, u64);
+  };
+}
+
+impl<T: Scalar> Add<Dual<T>> for Dual<T> {
+  type Output = Dual<T>;
+
+  fn add(self, rhs: Dual<T>) -> Self::Output {
+    Dual::new(self.value + rhs.value, self.tangent + rhs.tangent)
+  }
+}
+
+impl<T: Scalar> Sub<Dual<T>> for Dual<T> {
+  type Output = Dual<T>;
+
+  fn sub(self, rhs: Dual<T>) -> Self::Output {
+    Dual::new(self.value - rhs.value, self.tangent - rhs.tangent)
+  }
+}
+
+impl<T: Scalar> Neg for Dual<T> {
+  type Output = Dual<T>;
+
+  fn neg(self) -> Self::Output {
+    Dual::new(-self.value, -self.tangent)
+  }
+}
+
+impl<T: Scalar> Neg for &Dual<T> {
+  type Output = Dual<T>;
+
+  fn neg(self) -> Self::Output {
+    (*self).neg()
+  }
+}
+
+impl<T: Scalar> Mul<Dual<T>> for Dual<T> {
+  type Output = Dual<T>;
+
+  fn mul(self, rhs: Dual<T>) -> Self::Output {
+    let (a, b) = (self.value, rhs.value);
+    Dual::new(a * b, self.tangent * b + rhs.tangent * a)
+  }
+}
+
+impl<T: Scalar> Div<Dual<T>> for Dual<T> {
+  type Output = Dual<T>;
+
+  #[allow(clippy::suspicious_arithmetic_impl)]
+  fn div(self, rhs: Dual<T>) -> Self::Output {
+    let (a, b) = (self.value, rhs.value);
+    Dual::new(a / b, self.tangent / b - rhs.tangent * a / (b * b))
+  }
+}
+
+impl_dual_num!(f64);
+impl_dual_num!(f32);
+impl_dual_num!(i8);
+impl_dual_num!(u8);
+impl_dual_num!(i16);
+impl_dual_num!(u16);
+impl_dual_num!(i32);
+impl_dual_num!(i64);
+impl_dual_num!(u32);
+impl_dual_num!(u64);
+
+impl_dual_op!(Add, add, AddAssign, add_assign);
+impl_dual_op!(Sub, sub, SubAssign, sub_assign);
+impl_dual_op!(Mul, mul, MulAssign, mul_assign);
+impl_dual_op!(Div, div, DivAssign, div_assign);
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{Value, grad};
+
+  #[test]
+  fn test_dual_ops() {
+    let x = Dual::new(3.0, 1.0);
+    let y = Dual::constant(2.0);
+
+    assert_eq!(x + y, Dual::new(5.0, 1.0));
+    assert_eq!(x - y, Dual::new(1.0, 1.0));
+    assert_eq!(-x, Dual::new(-3.0, -1.0));
+    assert_eq!(x * y, Dual::new(6.0, 2.0));
+    assert_eq!(y / x, Dual::new(2.0 / 3.0, -2.0 / 9.0));
+    assert_eq!(2 * &x + 1.5, Dual::new(7.5, 2.0));
+    assert_eq!(1 / x, Dual::new(1.0 / 3.0, -1.0 / 9.0));
+
+    let mut z = x;
+    z *= &x;
+    z -= 1;
+    assert_eq!(z, Dual::new(8.0, 6.0));
+  }
+
+  #[test]
+  fn test_dual_fns() {
+    assert_eq!(Dual::new(-2.0, 1.0).relu(), Dual::new(0.0, 0.0));
+    assert_eq!(Dual::new(3.0, 2.0).relu(), Dual::new(3.0, 2.0));
+
+    let t = Dual::new(-2.0, 1.0).tanh();
+    assert_eq!(t.value(), -0.9640275800758169);
+    assert_eq!(t.tangent(), 0.07065082485316443);
+  }
+
+  fn assert_jvp_matches_reverse_mode<T: Scalar>(tol: T) {
+    fn f_dual<T: Scalar>(x: &[Dual<T>]) -> Vec<Dual<T>> {
+      let (a, b) = (x[0], x[1]);
+      vec![(a * b).tanh() + (a - b).relu() / b, a * a * b]
+    }
+
+    fn f_value<T: Scalar>(x: &[Value<T>]) -> Vec<Value<T>> {
+      let (a, b) = (&x[0], &x[1]);
+      vec![(a * b).tanh() + (a - b).relu() / b, a * a * b]
+    }
+
+    let x = [T::lit(1.5), T::lit(0.5)];
+    let values = x.iter().map(|&v| Value::new(v, None)).collect::<Vec<_>>();
+    let outputs = f_value(&values);
+
+    let (zero, one) = (T::zero(), T::one());
+    for (i, tangent) in [[one, zero], [zero, one]].iter().enumerate() {
+      let (ys, jv) = jvp(f_dual, &x, tangent);
+      for (j, output) in outputs.iter().enumerate() {
+        assert_eq!(ys[j], output.data());
+        let column = grad(output, &values);
+        assert!((jv[j] - column[i]).abs() < tol);
+      }
+    }
+  }
+
+  #[test]
+  fn test_jvp_matches_reverse_mode() {
+    assert_jvp_matches_reverse_mode(1e-12f64);
+    assert_jvp_matches_reverse_mode(1e-5f32);
+  }
+}
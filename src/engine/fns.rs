@@ -1,57 +1,353 @@
-use crate::Value;
+use crate::{Scalar, Value};
 
-impl Value {
-  pub fn relu(&self) -> Value {
-    let input = self.data();
-    let value = if input > 0.0 { input } else { 0.0 };
-    let mut result = Value::new(value, None);
-    result.set_op(Some("relu"));
-    result.add_prev(&[self]);
+use super::op_kind::OpInfo;
+use super::piecewise::clamp_local_grad;
+use super::scalar::clamp;
 
-    let mut tmp_self = self.clone();
+impl<T: Scalar> Value<T> {
+  pub fn relu(&self) -> Value<T> {
+    Value::from_op(
+      |inputs| {
+        let x = inputs[0];
+        if x > T::zero() { x } else { T::zero() }
+      },
+      "relu",
+      &[self],
+      |inputs, _, grad| {
+        let local_grad = if inputs[0] > T::zero() {
+          T::one()
+        } else {
+          T::zero()
+        };
+        vec![grad * local_grad]
+      },
+      |prev, _, grad| {
+        let local_grad = if prev[0].data() > T::zero() { 1.0 } else { 0.0 };
+        vec![grad * local_grad]
+      },
+    )
+  }
+
+  pub fn tanh(&self) -> Value<T> {
+    Value::from_op(
+      |inputs| inputs[0].tanh(),
+      "tanh",
+      &[self],
+      |_, value, grad| {
+        let local_grad = T::one() - value * value;
+        vec![grad * local_grad]
+      },
+      |_, out, grad| vec![grad * (1.0 - out * out)],
+    )
+  }
+
+  /// Passes the data through unchanged but blocks the gradient. Unlike
+  /// [`Value::detach`] the result stays connected to `self`, so it shows up
+  /// in the rendered graph.
+  pub fn stop_gradient(&self) -> Value<T> {
+    Value::from_op(
+      |inputs| inputs[0],
+      "stop_gradient",
+      &[self],
+      |_, _, _| vec![T::zero()],
+      |_, _, _| vec![Value::constant(T::zero())],
+    )
+  }
+
+  pub fn sigmoid(&self) -> Value<T> {
+    Value::from_op(
+      |inputs| sigmoid(inputs[0]),
+      "sigmoid",
+      &[self],
+      |_, value, grad| vec![grad * value * (T::one() - value)],
+      |_, out, grad| vec![grad * out * (1.0 - out)],
+    )
+  }
+
+  /// Like [`Value::relu`] but with slope `alpha` for negative inputs. The
+  /// gradient at zero is `alpha`, matching relu's choice of the left slope.
+  pub fn leaky_relu(&self, alpha: f64) -> Value<T> {
+    let a = T::lit(alpha);
+    Value::from_op(
+      move |inputs| {
+        let x = inputs[0];
+        if x > T::zero() { x } else { a * x }
+      },
+      OpInfo::with_params(
+        format!("leaky_relu({})", alpha),
+        "leaky_relu",
+        vec![alpha.to_bits()],
+      ),
+      &[self],
+      move |inputs, _, grad| {
+        let local_grad = if inputs[0] > T::zero() { T::one() } else { a };
+        vec![grad * local_grad]
+      },
+      move |prev, _, grad| {
+        let local_grad = if prev[0].data() > T::zero() {
+          1.0
+        } else {
+          alpha
+        };
+        vec![grad * local_grad]
+      },
+    )
+  }
+
+  /// Exponential linear unit, `x` for positive inputs and
+  /// `alpha * (exp(x) - 1)` otherwise.
+  pub fn elu(&self, alpha: f64) -> Value<T> {
+    let a = T::lit(alpha);
+    Value::from_op(
+      move |inputs| {
+        let x = inputs[0];
+        if x > T::zero() { x } else { a * x.exp_m1() }
+      },
+      OpInfo::with_params(
+        format!("elu({})", alpha),
+        "elu",
+        vec![alpha.to_bits()],
+      ),
+      &[self],
+      move |inputs, _, grad| {
+        let x = inputs[0];
+        let local_grad = if x > T::zero() { T::one() } else { a * x.exp() };
+        vec![grad * local_grad]
+      },
+      move |prev, _, grad| {
+        let x = &prev[0];
+        if x.data() > T::zero() {
+          vec![grad.clone()]
+        } else {
+          vec![grad * alpha * x.exp()]
+        }
+      },
+    )
+  }
+
+  /// Scaled exponential linear unit with the self-normalising constants from
+  /// Klambauer et al.
+  pub fn selu(&self) -> Value<T> {
+    let (lambda, alpha) = (T::lit(SELU_LAMBDA), T::lit(SELU_ALPHA));
+    Value::from_op(
+      move |inputs| {
+        let x = inputs[0];
+        if x > T::zero() {
+          lambda * x
+        } else {
+          lambda * alpha * x.exp_m1()
+        }
+      },
+      "selu",
+      &[self],
+      move |inputs, _, grad| {
+        let x = inputs[0];
+        let local_grad = if x > T::zero() {
+          lambda
+        } else {
+          lambda * alpha * x.exp()
+        };
+        vec![grad * local_grad]
+      },
+      |prev, _, grad| {
+        let x = &prev[0];
+        if x.data() > T::zero() {
+          vec![grad * SELU_LAMBDA]
+        } else {
+          vec![grad * (SELU_LAMBDA * SELU_ALPHA) * x.exp()]
+        }
+      },
+    )
+  }
+
+  /// Gaussian error linear unit, `x * Φ(x)` with the exact normal CDF.
+  pub fn gelu(&self) -> Value<T> {
+    Value::from_op(
+      |inputs| inputs[0] * normal_cdf(inputs[0]),
+      "gelu",
+      &[self],
+      |inputs, _, grad| {
+        let x = inputs[0];
+        vec![grad * (normal_cdf(x) + x * normal_pdf(x))]
+      },
+      |prev, _, grad| {
+        let x = &prev[0];
+        vec![grad * (x.normal_cdf() + x * x.normal_pdf())]
+      },
+    )
+  }
+
+  /// GELU with the tanh approximation of the normal CDF used by GPT-2 and
+  /// BERT.
+  pub fn gelu_tanh(&self) -> Value<T> {
+    let half = T::lit(0.5);
+    Value::from_op(
+      move |inputs| {
+        let x = inputs[0];
+        half * x * (T::one() + gelu_tanh_inner(x))
+      },
+      "gelu_tanh",
+      &[self],
+      move |inputs, _, grad| {
+        let x = inputs[0];
+        let t = gelu_tanh_inner(x);
+        let dinner = T::lit(SQRT_2_OVER_PI)
+          * (T::one() + T::lit(3.0 * GELU_COEFF) * x * x);
+        let local_grad =
+          half * (T::one() + t) + half * x * (T::one() - t * t) * dinner;
+        vec![grad * local_grad]
+      },
+      |prev, _, grad| {
+        let x = &prev[0];
+        let x2 = x * x;
+        let t = ((x + GELU_COEFF * &x2 * x) * SQRT_2_OVER_PI).tanh();
+        let dinner = (3.0 * GELU_COEFF * x2 + 1.0) * SQRT_2_OVER_PI;
+        vec![grad * ((&t + 1.0) * 0.5 + x * 0.5 * (1.0 - &t * &t) * dinner)]
+      },
+    )
+  }
+
+  /// Sigmoid linear unit `x * sigmoid(x)`, also known as swish.
+  pub fn silu(&self) -> Value<T> {
+    Value::from_op(
+      |inputs| inputs[0] * sigmoid(inputs[0]),
+      "silu",
+      &[self],
+      |inputs, _, grad| {
+        let x = inputs[0];
+        let s = sigmoid(x);
+        vec![grad * s * (T::one() + x * (T::one() - s))]
+      },
+      |prev, _, grad| {
+        let x = &prev[0];
+        let s = x.sigmoid();
+        vec![grad * &s * (x * (1.0 - &s) + 1.0)]
+      },
+    )
+  }
+
+  /// `ln(1 + exp(x))`, computed without overflow for large inputs.
+  pub fn softplus(&self) -> Value<T> {
+    Value::from_op(
+      |inputs| softplus(inputs[0]),
+      "softplus",
+      &[self],
+      |inputs, _, grad| vec![grad * sigmoid(inputs[0])],
+      |prev, _, grad| vec![grad * prev[0].sigmoid()],
+    )
+  }
 
-    result.set_grad_fn(Box::new(move |grad| {
-      let local_grad = if input > 0.0 { 1.0 } else { 0.0 };
-      tmp_self.add_grad(grad * local_grad);
-    }));
+  /// `x * tanh(softplus(x))`.
+  pub fn mish(&self) -> Value<T> {
+    Value::from_op(
+      |inputs| inputs[0] * softplus(inputs[0]).tanh(),
+      "mish",
+      &[self],
+      |inputs, _, grad| {
+        let x = inputs[0];
+        let t = softplus(x).tanh();
+        vec![grad * (t + x * (T::one() - t * t) * sigmoid(x))]
+      },
+      |prev, _, grad| {
+        let x = &prev[0];
+        let t = x.softplus().tanh();
+        vec![grad * (&t + x * (1.0 - &t * &t) * x.sigmoid())]
+      },
+    )
+  }
 
-    result
+  /// Clamps the input to `[-1, 1]`, like [`Value::clamp`]. The gradient is 1
+  /// inside the interval, including on its bounds, and 0 outside it.
+  pub fn hard_tanh(&self) -> Value<T> {
+    Value::from_op(
+      |inputs| clamp(inputs[0], -T::one(), T::one()),
+      "hard_tanh",
+      &[self],
+      |inputs, _, grad| vec![grad * hard_tanh_local_grad(inputs[0])],
+      |prev, _, grad| {
+        let local_grad = hard_tanh_local_grad(prev[0].data());
+        vec![grad * Value::constant(local_grad)]
+      },
+    )
   }
 
-  pub fn tanh(&self) -> Value {
-    let input = self.data();
-    let value = input.tanh();
-    let mut result = Value::new(value, None);
-    result.set_op(Some("tanh"));
-    result.add_prev(&[self]);
+  /// The standard normal CDF as a node of its own, so that the gradient of
+  /// [`Value::gelu`] stays differentiable.
+  fn normal_cdf(&self) -> Value<T> {
+    Value::from_op(
+      |inputs| normal_cdf(inputs[0]),
+      "normal_cdf",
+      &[self],
+      |inputs, _, grad| vec![grad * normal_pdf(inputs[0])],
+      |prev, _, grad| vec![grad * prev[0].normal_pdf()],
+    )
+  }
 
-    let mut tmp_self = self.clone();
+  fn normal_pdf(&self) -> Value<T> {
+    let exponent: Value<T> = self * self * -0.5;
+    exponent.exp() * FRAC_1_SQRT_2PI
+  }
+}
 
-    result.set_grad_fn(Box::new(move |grad| {
-      let local_grad = 1.0 - value * value;
-      tmp_self.add_grad(grad * local_grad);
-    }));
+const SELU_LAMBDA: f64 = 1.0507009873554805;
+const SELU_ALPHA: f64 = 1.6732632423543772;
+const GELU_COEFF: f64 = 0.044715;
+const SQRT_2_OVER_PI: f64 = 0.7978845608028654;
+const FRAC_1_SQRT_2PI: f64 = 0.3989422804014327;
 
-    result
+fn sigmoid<T: Scalar>(x: T) -> T {
+  if x >= T::zero() {
+    T::one() / (T::one() + (-x).exp())
+  } else {
+    let e = x.exp();
+    e / (T::one() + e)
   }
 }
 
+fn softplus<T: Scalar>(x: T) -> T {
+  x.max(T::zero()) + (-x.abs()).exp().ln_1p()
+}
+
+/// `erfc` is only available for `f64`, so other scalar types round-trip
+/// through it.
+fn normal_cdf<T: Scalar>(x: T) -> T {
+  let x = x
+    .to_f64()
+    .expect("normal_cdf: input not representable as f64");
+  T::lit(0.5 * libm::erfc(-x * std::f64::consts::FRAC_1_SQRT_2))
+}
+
+fn normal_pdf<T: Scalar>(x: T) -> T {
+  (T::lit(-0.5) * x * x).exp() * T::lit(FRAC_1_SQRT_2PI)
+}
+
+fn gelu_tanh_inner<T: Scalar>(x: T) -> T {
+  (T::lit(SQRT_2_OVER_PI) * (x + T::lit(GELU_COEFF) * x * x * x)).tanh()
+}
+
+fn hard_tanh_local_grad<T: Scalar>(x: T) -> T {
+  clamp_local_grad(x, -T::one(), T::one())
+}
+
 #[cfg(test)]
 mod tests {
   use crate::Value;
+  use crate::engine::gradcheck::assert_gradients;
+
+  const POINTS: [f64; 6] = [-3.0, -1.2, -0.3, 0.4, 1.1, 2.5];
 
   #[test]
   fn test_relu() {
     let x: Value = (-2.0).into();
     let mut relu_x = x.relu();
     assert_eq!(relu_x.data(), 0.0);
-    relu_x.backward();
+    relu_x.backward().unwrap();
     assert_eq!(x.grad(), 0.0);
 
     let y: Value = 3.0.into();
     let mut relu_y = y.relu();
     assert_eq!(relu_y.data(), 3.0);
-    relu_y.backward();
+    relu_y.backward().unwrap();
     assert_eq!(y.grad(), 1.0);
   }
 
@@ -60,19 +356,187 @@ mod tests {
     let x: Value = (-2.0).into();
     let mut tanh_x = x.tanh();
     assert_eq!(tanh_x.data(), -0.9640275800758169);
-    tanh_x.backward();
+    tanh_x.backward().unwrap();
     assert_eq!(x.grad(), 0.07065082485316443);
 
     let y: Value = 3.0.into();
     let mut tanh_y = y.tanh();
     assert_eq!(tanh_y.data(), 0.9950547536867305);
-    tanh_y.backward();
+    tanh_y.backward().unwrap();
     assert_eq!(y.grad(), 0.009866037165440211);
 
     let z: Value = 0.0.into();
     let mut tanh_z = z.tanh();
     assert_eq!(tanh_z.data(), 0.0);
-    tanh_z.backward();
+    tanh_z.backward().unwrap();
     assert_eq!(z.grad(), 1.0);
   }
+
+  #[test]
+  fn test_stop_gradient() {
+    let x = Value::new(3.0, Some("x"));
+    let y = Value::new(-2.0, Some("y"));
+    let mut z = &x * &y.stop_gradient() + &y;
+
+    z.backward_retain_graph().unwrap();
+
+    assert_eq!(z.data(), -8.0);
+    assert_eq!(x.grad(), -2.0);
+    assert_eq!(y.grad(), 1.0);
+    assert!(z.into_dot_str().contains("stop_gradient"));
+  }
+
+  #[test]
+  fn test_detach() {
+    let x = Value::new(3.0, Some("x"));
+    let h = (&x * &x).tanh();
+    let mut target = h.detach();
+    target.set_label("target");
+
+    assert_eq!(target.data(), h.data());
+    assert!(!target.requires_grad());
+    assert!(target.prev().is_empty());
+
+    let named = Value::new(1.5, Some("w")).detach();
+    assert_eq!(named.label(), "w");
+
+    let mut loss = (&h - &target) * &x;
+    loss.backward().unwrap();
+    assert_eq!(target.grad(), 0.0);
+    assert_eq!(x.grad(), 2.0 * 3.0 * (1.0 - h.data() * h.data()) * 3.0);
+  }
+
+  #[test]
+  fn test_sigmoid() {
+    assert_eq!(Value::new(0.0, None).sigmoid().data(), 0.5);
+    assert_eq!(Value::new(-1000.0, None).sigmoid().data(), 0.0);
+    assert_eq!(Value::new(1000.0, None).sigmoid().data(), 1.0);
+    for x in POINTS {
+      assert_gradients(|v| v[0].sigmoid(), &[x]);
+    }
+  }
+
+  #[test]
+  fn test_leaky_relu() {
+    let x = Value::new(-2.0, None);
+    let mut y = x.leaky_relu(0.1);
+    y.backward().unwrap();
+    assert_eq!(y.data(), -0.2);
+    assert_eq!(x.grad(), 0.1);
+    assert_eq!(y.op(), Some("leaky_relu(0.1)".to_string()));
+
+    let zero = Value::new(0.0, None);
+    zero.leaky_relu(0.1).backward().unwrap();
+    assert_eq!(zero.grad(), 0.1);
+
+    for x in POINTS {
+      assert_gradients(|v| v[0].leaky_relu(0.01), &[x]);
+    }
+  }
+
+  #[test]
+  fn test_elu() {
+    assert_eq!(Value::new(2.0, None).elu(1.0).data(), 2.0);
+    assert_eq!(
+      Value::new(-1.0, None).elu(0.5).data(),
+      0.5 * (-1f64).exp_m1()
+    );
+    for x in POINTS {
+      assert_gradients(|v| v[0].elu(1.5), &[x]);
+    }
+  }
+
+  #[test]
+  fn test_selu() {
+    assert_eq!(Value::new(1.0, None).selu().data(), 1.0507009873554805);
+    assert_eq!(
+      Value::new(-1000.0, None).selu().data(),
+      -1.0507009873554805 * 1.6732632423543772
+    );
+    for x in POINTS {
+      assert_gradients(|v| v[0].selu(), &[x]);
+    }
+  }
+
+  #[test]
+  fn test_gelu() {
+    assert_eq!(Value::new(0.0, None).gelu().data(), 0.0);
+    assert!(
+      (Value::new(1f64, None).gelu().data() - 0.8413447460685429).abs() < 1e-15
+    );
+    for x in POINTS {
+      assert_gradients(|v| v[0].gelu(), &[x]);
+      assert_gradients(|v| v[0].gelu_tanh(), &[x]);
+      let exact = Value::new(x, None).gelu().data();
+      let approx = Value::new(x, None).gelu_tanh().data();
+      assert!((exact - approx).abs() < 1e-3);
+    }
+  }
+
+  #[test]
+  fn test_silu() {
+    assert_eq!(Value::new(0.0, None).silu().data(), 0.0);
+    for x in POINTS {
+      assert_gradients(|v| v[0].silu(), &[x]);
+    }
+  }
+
+  #[test]
+  fn test_softplus() {
+    assert_eq!(Value::new(0.0, None).softplus().data(), 2f64.ln());
+    assert_eq!(Value::new(1000.0, None).softplus().data(), 1000.0);
+    assert_eq!(Value::new(-1000.0, None).softplus().data(), 0.0);
+    for x in POINTS {
+      assert_gradients(|v| v[0].softplus(), &[x]);
+    }
+  }
+
+  #[test]
+  fn test_mish() {
+    assert_eq!(Value::new(0.0, None).mish().data(), 0.0);
+    for x in POINTS {
+      assert_gradients(|v| v[0].mish(), &[x]);
+    }
+  }
+
+  #[test]
+  fn test_hard_tanh() {
+    assert_eq!(Value::new(-3.0, None).hard_tanh().data(), -1.0);
+    assert_eq!(Value::new(0.25, None).hard_tanh().data(), 0.25);
+
+    let edge = Value::new(1.0, None);
+    edge.hard_tanh().backward().unwrap();
+    assert_eq!(edge.grad(), 1.0);
+
+    // Same subgradient as clamping to [-1, 1], bounds included.
+    for x in [-1.5, -1.0, 0.0, 1.0, 1.5] {
+      let (a, b) = (Value::new(x, None), Value::new(x, None));
+      a.hard_tanh().backward().unwrap();
+      b.clamp(-1.0, 1.0).backward().unwrap();
+      assert_eq!(a.grad(), b.grad(), "at {}", x);
+    }
+
+    for x in POINTS {
+      assert_gradients(|v| v[0].hard_tanh(), &[x]);
+    }
+  }
+
+  #[test]
+  fn test_activations_are_single_nodes() {
+    let x = Value::new(0.5, Some("x"));
+    for y in [
+      x.sigmoid(),
+      x.leaky_relu(0.2),
+      x.elu(1.0),
+      x.selu(),
+      x.gelu(),
+      x.gelu_tanh(),
+      x.silu(),
+      x.softplus(),
+      x.mish(),
+      x.hard_tanh(),
+    ] {
+      assert_eq!(y.prev(), vec![x.clone()]);
+    }
+  }
 }
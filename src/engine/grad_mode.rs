@@ -0,0 +1,106 @@
+use std::cell::Cell;
+
+thread_local! {
+  static GRAD_ENABLED: Cell<bool> = const { Cell::new(true) };
+}
+
+/// Returns whether ops on the current thread record the graph needed for
+/// backpropagation.
+pub fn is_grad_enabled() -> bool {
+  GRAD_ENABLED.with(|enabled| enabled.get())
+}
+
+/// Runs `f` with graph recording turned off on the current thread. Every op
+/// evaluated inside returns a constant without inputs or gradient functions,
+/// which makes inference cheaper.
+pub fn no_grad<R>(f: impl FnOnce() -> R) -> R {
+  let _guard = GradModeGuard::new(false);
+  f()
+}
+
+/// Runs `f` with graph recording turned on, for code that needs a graph
+/// even when called inside [`no_grad`].
+pub fn with_grad<R>(f: impl FnOnce() -> R) -> R {
+  let _guard = GradModeGuard::new(true);
+  f()
+}
+
+/// Restores the previous grad mode when dropped, even if `f` panics.
+struct GradModeGuard {
+  prev: bool,
+}
+
+impl GradModeGuard {
+  fn new(enabled: bool) -> Self {
+    let prev = GRAD_ENABLED.with(|cell| cell.replace(enabled));
+    Self { prev }
+  }
+}
+
+impl Drop for GradModeGuard {
+  fn drop(&mut self) {
+    GRAD_ENABLED.with(|cell| cell.set(self.prev));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::Value;
+
+  #[test]
+  fn test_no_grad() {
+    let x = Value::new(2.0, Some("x"));
+
+    let y: Value = no_grad(|| {
+      assert!(!is_grad_enabled());
+      (&x * &x).tanh() + 1
+    });
+
+    assert!(is_grad_enabled());
+    assert_eq!(y.data(), 4.0f64.tanh() + 1.0);
+    assert!(!y.requires_grad());
+    assert!(y.prev().is_empty());
+    assert_eq!(y.op(), None);
+  }
+
+  #[test]
+  fn test_no_grad_nested_and_panic() {
+    no_grad(|| {
+      no_grad(|| {});
+      assert!(!is_grad_enabled());
+      with_grad(|| assert!(is_grad_enabled()));
+      assert!(!is_grad_enabled());
+    });
+    assert!(is_grad_enabled());
+
+    let result = std::panic::catch_unwind(|| no_grad(|| panic!("boom")));
+    assert!(result.is_err());
+    assert!(is_grad_enabled());
+  }
+
+  #[test]
+  fn test_requires_grad_propagation() {
+    let x = Value::new(3.0, Some("x"));
+    let c = Value::constant(2.0);
+    assert!(x.requires_grad());
+    assert!(!c.requires_grad());
+
+    let k: Value = &c * 4 - 1;
+    assert!(!k.requires_grad());
+    assert!(k.prev().is_empty());
+
+    let mut y = &x * &k;
+    assert!(y.requires_grad());
+    assert_eq!(y.prev().len(), 2);
+
+    y.backward().unwrap();
+    assert_eq!(x.grad(), 7.0);
+    assert_eq!(k.grad(), 0.0);
+
+    let mut frozen = Value::new(5.0, Some("frozen"));
+    frozen.set_requires_grad(false);
+    let z: Value = &frozen + 1;
+    assert!(!z.requires_grad());
+  }
+}
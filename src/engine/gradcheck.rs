@@ -0,0 +1,185 @@
+use crate::{Value, no_grad};
+
+use super::autograd::leaves;
+
+/// Outcome of [`gradcheck`].
+///
+/// The error is not purely relative: it is `|analytic - numeric| / max(1,
+/// |analytic|, |numeric|)`, so for gradients smaller than one in magnitude
+/// `tol` bounds the absolute difference, and only above that the relative
+/// one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GradCheck {
+  /// Worst error over all outputs, one entry per input.
+  pub errors: Vec<f64>,
+  /// Tolerance the errors were checked against.
+  pub tol: f64,
+}
+
+impl GradCheck {
+  pub fn passed(&self) -> bool {
+    self.errors.iter().all(|&e| e <= self.tol)
+  }
+
+  /// The worst error over all inputs, absolute or relative as described on
+  /// [`GradCheck`].
+  pub fn max_error(&self) -> f64 {
+    self.errors.iter().copied().fold(0.0, f64::max)
+  }
+}
+
+/// Compares the gradients [`Value::backward`] computes for `f` at `inputs`
+/// with central finite differences using step `eps`.
+///
+/// The error for each pair is `|analytic - numeric| / max(1, |analytic|,
+/// |numeric|)`, i.e. absolute for gradients below one and relative above,
+/// and the worst one over all outputs is reported for each input.
+///
+/// `f` is evaluated twice per input for the finite differences, each time
+/// giving the differences of every output, and once more per output to
+/// backpropagate from it.
+///
+/// The finite differences evaluate `f` under [`no_grad`], so an `f` that
+/// builds gradients of its own, e.g. with [`crate::grad_graph`], has to
+/// record them inside [`crate::with_grad`].
+pub fn gradcheck<F>(f: F, inputs: &[f64], eps: f64, tol: f64) -> GradCheck
+where
+  F: Fn(&[Value]) -> Vec<Value>,
+{
+  // One entry per input, holding the derivatives of every output.
+  let numeric = (0..inputs.len())
+    .map(|j| finite_difference(&f, inputs, j, eps))
+    .collect::<Vec<_>>();
+  let num_outputs = numeric.first().map_or(0, Vec::len);
+  let mut errors = vec![0.0; inputs.len()];
+
+  for i in 0..num_outputs {
+    let leaves = leaves(inputs);
+    let mut output = f(&leaves).remove(i);
+    output.backward().unwrap();
+
+    for (j, leaf) in leaves.iter().enumerate() {
+      let numeric = numeric[j][i];
+      let analytic = leaf.grad();
+      let scale = 1f64.max(analytic.abs()).max(numeric.abs());
+      let error = (analytic - numeric).abs() / scale;
+      errors[j] = f64::max(errors[j], error);
+    }
+  }
+
+  GradCheck { errors, tol }
+}
+
+/// The central differences of every output of `f` with respect to input
+/// `input`.
+fn finite_difference<F>(
+  f: &F,
+  inputs: &[f64],
+  input: usize,
+  eps: f64,
+) -> Vec<f64>
+where
+  F: Fn(&[Value]) -> Vec<Value>,
+{
+  let eval = |delta: f64| {
+    let mut x = inputs.to_vec();
+    x[input] += delta;
+    let outputs = no_grad(|| f(&leaves(&x)));
+    outputs.iter().map(Value::data).collect::<Vec<_>>()
+  };
+
+  let (plus, minus) = (eval(eps), eval(-eps));
+  plus
+    .iter()
+    .zip(minus)
+    .map(|(p, m)| (p - m) / (2.0 * eps))
+    .collect()
+}
+
+/// Checks the first-order gradients of `f` with [`gradcheck`], the
+/// second-order ones through [`crate::grad_graph`], and that both gradient
+/// forms agree. Shared by the tests of every op family.
+#[cfg(test)]
+pub(crate) fn assert_gradients<F>(f: F, inputs: &[f64])
+where
+  F: Fn(&[Value]) -> Value,
+{
+  use crate::{grad, grad_graph, with_grad};
+
+  let result = gradcheck(|x| vec![f(x)], inputs, 1e-6, 1e-6);
+  assert!(result.passed(), "{:?} at {:?}", result, inputs);
+
+  // The finite differences are evaluated under `no_grad`, but the gradient
+  // graph can only be built if `f` records its own.
+  let grad_f = |x: &[Value]| with_grad(|| grad_graph(&f(x), x));
+  let second_order = gradcheck(grad_f, inputs, 1e-5, 1e-5);
+  assert!(second_order.passed(), "{:?} at {:?}", second_order, inputs);
+
+  let x = leaves(inputs);
+  let y = f(&x);
+  for (g, expected) in grad_graph(&y, &x).iter().zip(grad(&y, &x)) {
+    assert!((g.data() - expected).abs() < 1e-12, "at {:?}", inputs);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn check<F>(f: F, inputs: &[f64])
+  where
+    F: Fn(&[Value]) -> Vec<Value>,
+  {
+    let result = gradcheck(f, inputs, 1e-6, 1e-6);
+    assert!(result.passed(), "{:?}", result);
+  }
+
+  #[test]
+  fn test_gradcheck_ops() {
+    let inputs = [1.3, -0.7];
+    check(|x| vec![&x[0] + &x[1]], &inputs);
+    check(|x| vec![&x[0] - &x[1]], &inputs);
+    check(|x| vec![-&x[0]], &inputs);
+    check(|x| vec![&x[0] * &x[1]], &inputs);
+    check(|x| vec![&x[0] / &x[1]], &inputs);
+    check(|x| vec![&x[0] * &x[0], 2.5 / &x[1] - 1], &inputs);
+  }
+
+  #[test]
+  fn test_gradcheck_fns() {
+    check(|x| vec![x[0].relu()], &[0.8]);
+    check(|x| vec![x[0].relu()], &[-0.8]);
+    check(|x| vec![x[0].tanh()], &[-2.0]);
+    check(|x| vec![x[0].tanh()], &[0.3]);
+  }
+
+  #[test]
+  fn test_gradcheck_evaluations() {
+    let calls = std::cell::Cell::new(0);
+    let f = |x: &[Value]| {
+      calls.set(calls.get() + 1);
+      vec![&x[0] * &x[1], x[0].tanh(), x[1].exp()]
+    };
+    check(f, &[0.4, -1.1]);
+    // Two per input for the differences and one per output for backward.
+    assert_eq!(calls.get(), 2 * 2 + 3);
+  }
+
+  #[test]
+  fn test_gradcheck_detects_wrong_gradient() {
+    let result = gradcheck(
+      |x| {
+        let mut y = x[0].tanh();
+        y.set_grad_fn(Box::new(|_, _, grad| vec![2.0 * grad]));
+        vec![y]
+      },
+      &[0.5],
+      1e-6,
+      1e-6,
+    );
+
+    assert!(!result.passed());
+    let t = 0.5f64.tanh();
+    assert!((result.max_error() - (2.0 - (1.0 - t * t)) / 2.0).abs() < 1e-6);
+  }
+}
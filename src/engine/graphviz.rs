@@ -7,9 +7,11 @@ use graphviz_rust::{
 };
 use linked_hash_set::LinkedHashSet;
 
-use crate::Value;
+use crate::{Scalar, Value};
 
-impl Value {
+use super::backprop::build_topo;
+
+impl<T: Scalar> Value<T> {
   pub fn into_dot(&self) -> Graph {
     let (mut nodes, mut edges) = self.trace_graph();
     nodes.sort_by_key(|x| x.id());
@@ -75,28 +77,17 @@ impl Value {
     String::from_utf8(svg_data).unwrap()
   }
 
-  fn trace_graph(&self) -> (Vec<Value>, Vec<(Value, Value)>) {
-    let mut nodes = LinkedHashSet::<Value>::new();
-    let mut edges = LinkedHashSet::<(Value, Value)>::new();
-
-    fn build(
-      node: &Value,
-      nodes: &mut LinkedHashSet<Value>,
-      edges: &mut LinkedHashSet<(Value, Value)>,
-    ) {
-      if nodes.contains(node) {
-        return;
-      }
-      nodes.insert(node.clone());
+  #[allow(clippy::type_complexity)]
+  fn trace_graph(&self) -> (Vec<Value<T>>, Vec<(Value<T>, Value<T>)>) {
+    let nodes = build_topo(self);
+    let mut edges = LinkedHashSet::<(Value<T>, Value<T>)>::new();
+
+    for node in nodes.iter() {
       for child in node.prev().iter() {
         edges.insert((child.clone(), node.clone()));
-        build(child, nodes, edges);
       }
     }
 
-    build(self, &mut nodes, &mut edges);
-
-    let nodes = nodes.iter().cloned().collect::<Vec<_>>();
     let edges = edges.iter().cloned().collect::<Vec<_>>();
 
     (nodes, edges)
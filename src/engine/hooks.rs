@@ -0,0 +1,184 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{Scalar, Value};
+
+use super::ValueInner;
+use super::shared::{Lock, Shared, Weak};
+
+static NEXT_HOOK_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Removes a hook registered with [`Value::register_hook`]. Dropping the
+/// handle keeps the hook.
+pub struct HookHandle<T: Scalar = f64> {
+  node: Weak<Lock<ValueInner<T>>>,
+  id: usize,
+}
+
+impl<T: Scalar> HookHandle<T> {
+  pub fn remove(self) {
+    if let Some(node) = self.node.upgrade() {
+      node.borrow_mut().hooks.retain(|(id, _)| *id != self.id);
+    }
+  }
+}
+
+impl<T: Scalar> Value<T> {
+  /// Registers `hook` to run during [`Value::backward`] once the gradient of
+  /// this node is final, before it is passed on to the inputs. The gradient
+  /// is replaced by the hook's result, so a hook can inspect, log or rewrite
+  /// it. Hooks run in the order they were registered.
+  ///
+  /// A hook sees the gradient of the current pass only: on a leaf, it runs
+  /// before the gradient is added to the one accumulated by earlier passes.
+  /// Hooks on intermediate nodes are dropped with the graph when `backward`
+  /// releases it.
+  ///
+  /// [`crate::backward_many`], [`crate::CompiledGraph::backward`],
+  /// [`crate::grad`] and `parallel_backward` run hooks the same way.
+  /// [`crate::grad_graph`] doesn't, since its gradients are graphs rather
+  /// than numbers.
+  pub fn register_hook(
+    &self,
+    hook: impl Fn(T) -> T + Send + Sync + 'static,
+  ) -> HookHandle<T> {
+    let id = NEXT_HOOK_ID.fetch_add(1, Ordering::Relaxed);
+    let hook = Shared::new(hook);
+    self.node().borrow_mut().hooks.push((id, hook));
+    HookHandle {
+      node: Shared::downgrade(self.node()),
+      id,
+    }
+  }
+
+  pub(crate) fn has_hooks(&self) -> bool {
+    !self.node().borrow().hooks.is_empty()
+  }
+
+  /// Replaces the gradient by the result of the hooks.
+  pub(crate) fn run_hooks(&mut self) {
+    if self.has_hooks() {
+      let grad = self.apply_hooks(self.grad());
+      self.set_grad(grad);
+    }
+  }
+
+  /// Returns `grad` as rewritten by the hooks. The hooks are called without
+  /// holding the node, so they can read it.
+  pub(crate) fn apply_hooks(&self, grad: T) -> T {
+    if !self.requires_grad() || !self.has_hooks() {
+      return grad;
+    }
+    let hooks = self.node().borrow().hooks.clone();
+    hooks.iter().fold(grad, |grad, (_, hook)| hook(grad))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::AtomicU32;
+
+  use super::*;
+
+  #[test]
+  fn test_hook_rewrites_gradient() {
+    let x = Value::new(3.0, Some("x"));
+    let y: Value = &x * &x;
+    y.register_hook(|g: f64| g.clamp(-0.5, 0.5));
+    let mut z: Value = &y * 4;
+
+    z.backward().unwrap();
+
+    assert_eq!(x.grad(), 0.5 * 2.0 * 3.0);
+  }
+
+  #[test]
+  fn test_hook_sees_final_gradient() {
+    static SEEN: AtomicU32 = AtomicU32::new(0);
+    let x = Value::new(1.0, Some("x"));
+    let t: Value = &x * 2;
+    t.register_hook(|g| {
+      SEEN.store(g as u32, Ordering::SeqCst);
+      g
+    });
+    let mut y: Value = &t + &t + &t * 3;
+
+    y.backward().unwrap();
+
+    assert_eq!(SEEN.load(Ordering::SeqCst), 5);
+    assert_eq!(x.grad(), 10.0);
+  }
+
+  #[test]
+  fn test_hooks_on_leaf() {
+    let x = Value::new(2.0, Some("x"));
+    x.register_hook(|g| g * 10.0);
+    x.register_hook(|g| g + 1.0);
+
+    let mut y: Value = &x * 3;
+    y.backward().unwrap();
+    assert_eq!(x.grad(), 31.0);
+
+    // The hooks only apply to the gradient of the new pass.
+    let mut y: Value = &x * 3;
+    y.backward().unwrap();
+    assert_eq!(x.grad(), 62.0);
+  }
+
+  #[test]
+  fn test_grad_runs_hooks() {
+    let x = Value::new(3.0, Some("x"));
+    let y: Value = &x * &x;
+    y.register_hook(|g: f64| g.clamp(-0.5, 0.5));
+    x.register_hook(|g| g + 1.0);
+    let z: Value = &y * 4;
+
+    assert_eq!(crate::grad(&z, &[x.clone()]), [0.5 * 2.0 * 3.0 + 1.0]);
+    assert_eq!(x.grad(), 0.0);
+
+    // Gradient graphs are built without the hooks.
+    let g = crate::grad_graph(&z, &[x.clone()]).remove(0);
+    assert_eq!(g.data(), 4.0 * 2.0 * 3.0);
+  }
+
+  #[test]
+  fn test_compiled_backward_runs_hooks() {
+    let x = Value::placeholder("x");
+    let y: Value = &x * &x;
+    y.register_hook(|g: f64| g.clamp(-0.5, 0.5));
+    x.register_hook(|g| g + 1.0);
+    let z: Value = &y * 4;
+    let mut graph = crate::CompiledGraph::new(&[x.clone()], &[z]);
+
+    graph.run(&[3.0]).unwrap();
+    graph.backward().unwrap();
+    assert_eq!(x.grad(), 0.5 * 2.0 * 3.0 + 1.0);
+
+    // As with backward, the leaf's hook only sees the new pass.
+    graph.backward().unwrap();
+    assert_eq!(x.grad(), 2.0 * (0.5 * 2.0 * 3.0 + 1.0));
+  }
+
+  #[test]
+  fn test_remove_hook() {
+    let x = Value::new(2.0, Some("x"));
+    let doubled = x.register_hook(|g| g * 2.0);
+    let negated = x.register_hook(|g: f64| -g);
+
+    doubled.remove();
+    let mut y: Value = &x * 3;
+    y.backward().unwrap();
+    assert_eq!(x.grad(), -3.0);
+
+    negated.remove();
+    assert!(!x.has_hooks());
+  }
+
+  #[test]
+  fn test_handle_outlives_value() {
+    let handle = {
+      let x = Value::new(1.0, None);
+      x.register_hook(|g: f64| g)
+    };
+    handle.remove();
+  }
+}
@@ -0,0 +1,231 @@
+use crate::{Scalar, Value};
+
+use super::op_kind::OpInfo;
+
+impl<T: Scalar> Value<T> {
+  pub fn exp(&self) -> Value<T> {
+    Value::from_op(
+      |inputs| inputs[0].exp(),
+      "exp",
+      &[self],
+      |_, value, grad| vec![grad * value],
+      |_, out, grad| vec![grad * out],
+    )
+  }
+
+  /// Natural logarithm. `ln(0)` is `-inf` with an infinite gradient, and the
+  /// logarithm of a negative number is NaN, as for `f64::ln`.
+  pub fn ln(&self) -> Value<T> {
+    Value::from_op(
+      |inputs| inputs[0].ln(),
+      "ln",
+      &[self],
+      |inputs, _, grad| vec![grad / inputs[0]],
+      |prev, _, grad| vec![grad / &prev[0]],
+    )
+  }
+
+  /// Raises `self` to the constant power `exponent`. A negative base with a
+  /// fractional exponent gives NaN, as for `f64::powf`. The gradient for an
+  /// exponent of zero is zero everywhere, including at a zero base.
+  pub fn powf(&self, exponent: f64) -> Value<T> {
+    let k = T::lit(exponent);
+    Value::from_op(
+      move |inputs| inputs[0].powf(k),
+      OpInfo::with_params(
+        format!("^{}", exponent),
+        "powf",
+        vec![exponent.to_bits()],
+      ),
+      &[self],
+      move |inputs, _, grad| vec![grad * powf_local_grad(inputs[0], k)],
+      move |prev, _, grad| {
+        if exponent == 0.0 {
+          return vec![Value::constant(T::zero())];
+        }
+        vec![grad * exponent * prev[0].powf(exponent - 1.0)]
+      },
+    )
+  }
+
+  /// Raises `self` to the power `exponent`, differentiable in both.
+  ///
+  /// The gradient with respect to the exponent is `out * ln(self)`. It is
+  /// taken as zero for a zero base, where the result doesn't depend on the
+  /// exponent, and is NaN for a negative base, where the logarithm is
+  /// undefined. A negative base only has a real result for integer exponents.
+  /// The gradient built by [`crate::grad_graph`] is NaN for a zero base and
+  /// a zero exponent, where `0 * 0^-1` is undefined.
+  pub fn pow(&self, exponent: &Value<T>) -> Value<T> {
+    Value::from_op(
+      |inputs| inputs[0].powf(inputs[1]),
+      "^",
+      &[self, exponent],
+      |inputs, value, grad| {
+        let (a, b) = (inputs[0], inputs[1]);
+        let exponent_grad = if a.is_zero() {
+          T::zero()
+        } else {
+          value * a.ln()
+        };
+        vec![grad * powf_local_grad(a, b), grad * exponent_grad]
+      },
+      |prev, out, grad| {
+        let (a, b) = (&prev[0], &prev[1]);
+        // Unlike the first-order gradient, this isn't cut off at a zero
+        // exponent: its value is zero there but its derivative with respect
+        // to the exponent is not.
+        let base_grad = grad * b * a.pow(&(b - 1.0));
+        let exponent_grad = if a.data().is_zero() {
+          Value::constant(T::zero())
+        } else {
+          grad * out * a.ln()
+        };
+        vec![base_grad, exponent_grad]
+      },
+    )
+  }
+
+  /// Square root. `sqrt(0)` has an infinite gradient and the square root of
+  /// a negative number is NaN.
+  pub fn sqrt(&self) -> Value<T> {
+    Value::from_op(
+      |inputs| inputs[0].sqrt(),
+      "sqrt",
+      &[self],
+      |_, value, grad| vec![grad * T::lit(0.5) / value],
+      |_, out, grad| vec![grad * 0.5 / out],
+    )
+  }
+}
+
+pub(super) fn powf_local_grad<T: Scalar>(base: T, exponent: T) -> T {
+  if exponent.is_zero() {
+    T::zero()
+  } else {
+    exponent * base.powf(exponent - T::one())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::engine::gradcheck::assert_gradients;
+  use crate::{Value, hessian};
+
+  #[test]
+  fn test_exp() {
+    let x = Value::new(2.0, None);
+    let mut y = x.exp();
+    y.backward().unwrap();
+    assert_eq!(y.data(), 2f64.exp());
+    assert_eq!(x.grad(), 2f64.exp());
+    assert_eq!(y.op(), Some("exp".to_string()));
+
+    assert_gradients(|x| x[0].exp(), &[-1.5]);
+    assert_gradients(|x| (&x[0] * &x[1]).exp(), &[0.5, 1.2]);
+    assert_eq!(Value::new(1000.0, None).exp().data(), f64::INFINITY);
+  }
+
+  #[test]
+  fn test_ln() {
+    assert_gradients(|x| x[0].ln(), &[0.7]);
+    assert_gradients(|x| (&x[0] * &x[0] + Value::constant(1.0)).ln(), &[-2.0]);
+
+    let zero = Value::new(0.0, None);
+    let mut y = zero.ln();
+    y.backward().unwrap();
+    assert_eq!(y.data(), f64::NEG_INFINITY);
+    assert_eq!(zero.grad(), f64::INFINITY);
+
+    assert!(Value::new(-1f64, None).ln().data().is_nan());
+  }
+
+  #[test]
+  fn test_powf() {
+    assert_gradients(|x| x[0].powf(2.0), &[-1.5]);
+    assert_gradients(|x| x[0].powf(3.0), &[-1.5]);
+    assert_gradients(|x| x[0].powf(-1.0), &[0.8]);
+    assert_gradients(|x| x[0].powf(0.5), &[2.0]);
+    assert_gradients(|x| x[0].powf(2.7), &[1.3]);
+
+    let x = Value::new(-2.0, None);
+    let mut y = x.powf(3.0);
+    y.backward().unwrap();
+    assert_eq!(y.data(), -8.0);
+    assert_eq!(x.grad(), 12.0);
+    assert_eq!(y.op(), Some("^3".to_string()));
+
+    assert!(Value::new(-2f64, None).powf(0.5).data().is_nan());
+
+    let zero = Value::new(0.0, None);
+    let mut y = zero.powf(0.0);
+    y.backward().unwrap();
+    assert_eq!(y.data(), 1.0);
+    assert_eq!(zero.grad(), 0.0);
+  }
+
+  #[test]
+  fn test_pow() {
+    assert_gradients(|x| x[0].pow(&x[1]), &[1.7, 2.3]);
+    assert_gradients(|x| x[0].pow(&x[1]), &[0.4, -1.2]);
+    assert_gradients(
+      |x| (&x[0] + Value::constant(1.0)).pow(&(&x[1] * &x[0])),
+      &[0.5, 1.5],
+    );
+
+    let a = Value::new(2.0, None);
+    let b = Value::new(3.0, None);
+    let mut y = a.pow(&b);
+    y.backward().unwrap();
+    assert_eq!(y.data(), 8.0);
+    assert_eq!(a.grad(), 12.0);
+    assert_eq!(b.grad(), 8.0 * 2f64.ln());
+  }
+
+  #[test]
+  fn test_pow_domain_edges() {
+    let a = Value::new(0.0, None);
+    let b = Value::new(2.0, None);
+    let mut y = a.pow(&b);
+    y.backward().unwrap();
+    assert_eq!(y.data(), 0.0);
+    assert_eq!(a.grad(), 0.0);
+    assert_eq!(b.grad(), 0.0);
+
+    let a = Value::new(-2.0, None);
+    let b: Value = Value::new(2.0, None);
+    let mut y = a.pow(&b);
+    y.backward().unwrap();
+    assert_eq!(y.data(), 4.0);
+    assert_eq!(a.grad(), -4.0);
+    assert!(b.grad().is_nan());
+
+    let a: Value = Value::new(-2.0, None);
+    assert!(a.pow(&Value::new(0.5, None)).data().is_nan());
+  }
+
+  #[test]
+  fn test_pow_hessian_at_zero_exponent() {
+    let h = hessian(|x| x[0].pow(&x[1]), &[2.0, 0.0]);
+    let expected = [[0.0, 0.5], [0.5, 2f64.ln().powi(2)]];
+    for (row, expected) in h.iter().zip(expected) {
+      for (h, e) in row.iter().zip(expected) {
+        assert!((h - e).abs() < 1e-12, "{:?}", h);
+      }
+    }
+  }
+
+  #[test]
+  fn test_sqrt() {
+    assert_gradients(|x| x[0].sqrt(), &[2.5]);
+    assert_gradients(|x| (&x[0] * &x[1]).sqrt(), &[0.5, 3.0]);
+
+    let zero = Value::new(0.0, None);
+    let mut y = zero.sqrt();
+    y.backward().unwrap();
+    assert_eq!(y.data(), 0.0);
+    assert_eq!(zero.grad(), f64::INFINITY);
+
+    assert!(Value::new(-4f64, None).sqrt().data().is_nan());
+  }
+}
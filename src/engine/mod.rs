@@ -2,122 +2,460 @@ use lazy_static::lazy_static;
 use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::sync::atomic::AtomicU32;
-use std::{cell::RefCell, rc::Rc};
 
+mod anomaly;
+mod autograd;
 mod backprop;
+mod checkpoint;
+mod compiled;
+mod custom_op;
+mod dual;
 mod fns;
+mod grad_mode;
+mod gradcheck;
 mod graphviz;
+mod hooks;
+mod math;
+mod op_kind;
 mod ops;
+mod optimize;
+mod piecewise;
+mod reduce;
+mod scalar;
+mod shared;
+mod softmax;
+mod tape;
+mod trig;
+
+pub use anomaly::{Anomaly, detect_anomaly};
+pub use autograd::*;
+pub use backprop::{BackwardError, backward_many};
+pub use checkpoint::checkpoint;
+pub use compiled::CompiledGraph;
+pub use custom_op::CustomOp;
+pub use dual::*;
+pub use grad_mode::*;
+pub use gradcheck::*;
+pub use hooks::HookHandle;
+pub use scalar::Scalar;
+pub use tape::Tape;
+
+#[cfg(feature = "parallel")]
+pub(crate) use autograd::grad_impl;
+
+use op_kind::{OpInfo, OpKind};
+use shared::{Lock, Shared};
+use tape::Recording;
+
+/// Computes the gradients of a node's inputs from the input data, the node's
+/// own data and the upstream gradient, returning one gradient per input.
+pub type GradFn<T = f64> = Box<GradFnDyn<T>>;
+
+/// Builds the gradients of a node's inputs as `Value`s, given the inputs, the
+/// node itself and the upstream gradient. Used by [`grad_graph`] so that the
+/// gradients stay differentiable.
+pub type GradGraphFn<T = f64> = Box<GradGraphFnDyn<T>>;
+
+type ForwardFnDyn<T> = dyn Fn(&[T]) -> T + Send + Sync;
+
+type HookFnDyn<T> = dyn Fn(T) -> T + Send + Sync;
+
+type GradFnDyn<T> = dyn Fn(&[T], T, T) -> Vec<T> + Send + Sync;
+
+type GradGraphFnDyn<T> =
+  dyn Fn(&[Value<T>], &Value<T>, &Value<T>) -> Vec<Value<T>> + Send + Sync;
+
+/// A scalar node of the computation graph, holding `T` data and gradient.
+/// Inside [`Tape::record`] the ops record onto the tape instead, and their
+/// results are positions on it.
+#[derive(Clone)]
+pub struct Value<T: Scalar = f64> {
+  repr: Repr<T>,
+}
 
 #[derive(Clone)]
-pub struct Value {
-  inner: Rc<RefCell<ValueInner>>,
+enum Repr<T: Scalar> {
+  Node(Shared<Lock<ValueInner<T>>>),
+  /// A node of a tape, by its position.
+  Tape(Shared<Lock<Recording<T>>>, usize),
 }
 
-pub(crate) struct ValueInner {
+pub(crate) struct ValueInner<T: Scalar> {
   id: u32,
-  data: f64,
-  grad: f64,
+  data: T,
+  grad: T,
+  requires_grad: bool,
+  released: bool,
   label: String,
   op: Option<String>,
-  prev: Vec<Value>,
-  grad_fn: Option<Box<dyn FnMut(f64)>>,
+  kind: Option<OpKind>,
+  prev: Vec<Value<T>>,
+  forward_fn: Option<Shared<ForwardFnDyn<T>>>,
+  grad_fn: Option<Shared<GradFnDyn<T>>>,
+  grad_graph_fn: Option<Shared<GradGraphFnDyn<T>>>,
+  hooks: Vec<(usize, Shared<HookFnDyn<T>>)>,
 }
 
-impl Value {
-  pub fn new(data: f64, label: Option<&str>) -> Self {
+impl<T: Scalar> Value<T> {
+  pub fn new(data: T, label: Option<&str>) -> Self {
     lazy_static! {
       static ref ID_COUNTER: AtomicU32 = AtomicU32::new(0);
     }
-    let inner = Rc::new(RefCell::new(ValueInner {
+    let inner = Shared::new(Lock::new(ValueInner {
       id: ID_COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst),
       data,
-      grad: 0.0,
+      grad: T::zero(),
+      requires_grad: true,
+      released: false,
       label: label.unwrap_or_default().to_string(),
       op: None,
+      kind: None,
       prev: vec![],
+      forward_fn: None,
       grad_fn: None,
+      grad_graph_fn: None,
+      hooks: vec![],
     }));
-    Self { inner }
+    Self {
+      repr: Repr::Node(inner),
+    }
+  }
+
+  /// Creates a leaf that never requires a gradient, like the literals wrapped
+  /// by the numeric operator overloads.
+  pub fn constant(data: T) -> Self {
+    let mut result = Value::new(data, None);
+    result.set_requires_grad(false);
+    result
+  }
+
+  /// Returns a new leaf with the same data and label that is cut off from the
+  /// graph and doesn't require a gradient.
+  pub fn detach(&self) -> Value<T> {
+    let mut result = Value::constant(self.data());
+    if !self.is_recorded() {
+      result.set_label(&self.label());
+    }
+    result
+  }
+
+  /// Creates the result of an op, evaluating `forward` on the data of
+  /// `prev`. The op label, the inputs and the forward and gradient functions
+  /// are only recorded when grad mode is enabled and at least one input
+  /// requires a gradient; otherwise the result is a constant. The op goes on
+  /// a tape if an input is on one or a [`Tape`] is recording.
+  pub(crate) fn from_op(
+    forward: impl Fn(&[T]) -> T + Send + Sync + 'static,
+    op: impl Into<OpInfo>,
+    prev: &[&Value<T>],
+    grad_fn: impl Fn(&[T], T, T) -> Vec<T> + Send + Sync + 'static,
+    grad_graph_fn: impl Fn(&[Value<T>], &Value<T>, &Value<T>) -> Vec<Value<T>>
+    + Send
+    + Sync
+    + 'static,
+  ) -> Value<T> {
+    // Most ops take one or two inputs, whose data fits on the stack.
+    let data = match prev {
+      [a] => forward(&[a.data()]),
+      [a, b] => forward(&[a.data(), b.data()]),
+      _ => forward(&prev.iter().map(|v| v.data()).collect::<Vec<_>>()),
+    };
+    Value::from_op_with_data(data, forward, op, prev, grad_fn, grad_graph_fn)
+  }
+
+  /// Like [`Value::from_op`], for callers that already have the result of
+  /// `forward`.
+  pub(crate) fn from_op_with_data(
+    data: T,
+    forward: impl Fn(&[T]) -> T + Send + Sync + 'static,
+    op: impl Into<OpInfo>,
+    prev: &[&Value<T>],
+    grad_fn: impl Fn(&[T], T, T) -> Vec<T> + Send + Sync + 'static,
+    grad_graph_fn: impl Fn(&[Value<T>], &Value<T>, &Value<T>) -> Vec<Value<T>>
+    + Send
+    + Sync
+    + 'static,
+  ) -> Value<T> {
+    let op = op.into();
+    if !is_grad_enabled() || !prev.iter().any(|v| v.requires_grad()) {
+      let result = Value::constant(data);
+      anomaly::check_forward(&result, &op.label, prev);
+      return result;
+    }
+    if let Some(recording) = tape::recording_for(prev) {
+      return tape::push(recording, data, &op, prev, grad_fn);
+    }
+
+    let mut result = Value::new(data, None);
+    result.set_op(Some(&op.label));
+    result.add_prev(prev);
+    {
+      let mut inner = result.node().borrow_mut();
+      inner.kind = op.kind;
+      inner.forward_fn = Some(Shared::new(forward));
+      inner.grad_fn = Some(Shared::new(grad_fn));
+      inner.grad_graph_fn = Some(Shared::new(grad_graph_fn));
+    }
+    anomaly::check_forward(&result, &op.label, prev);
+    result
   }
 
-  pub(crate) fn inner(self) -> Rc<RefCell<ValueInner>> {
-    self.inner.clone()
+  /// The graph node behind this value.
+  ///
+  /// # Panics
+  ///
+  /// Panics if the value was recorded on a tape.
+  fn node(&self) -> &Shared<Lock<ValueInner<T>>> {
+    match &self.repr {
+      Repr::Node(node) => node,
+      Repr::Tape(..) => panic!(
+        "a value recorded on a tape only supports ops, data, grad, detach \
+         and backward"
+      ),
+    }
   }
 
-  pub(crate) fn set_inner(&mut self, inner: Rc<RefCell<ValueInner>>) {
-    self.inner = inner;
+  /// Returns whether this value was recorded on a [`Tape`].
+  pub(crate) fn is_recorded(&self) -> bool {
+    matches!(self.repr, Repr::Tape(..))
   }
 
   pub fn id(&self) -> u32 {
-    self.inner.borrow().id
+    self.node().borrow().id
   }
 
-  pub fn data(&self) -> f64 {
-    self.inner.borrow().data
+  pub fn data(&self) -> T {
+    match &self.repr {
+      Repr::Node(node) => node.borrow().data,
+      Repr::Tape(recording, index) => recording.borrow().data(*index),
+    }
   }
 
-  pub fn set_data(&mut self, data: f64) {
-    self.inner.borrow_mut().data = data;
+  pub fn set_data(&mut self, data: T) {
+    self.node().borrow_mut().data = data;
   }
 
-  pub fn grad(&self) -> f64 {
-    self.inner.borrow().grad
+  /// The gradient accumulated by backward passes. For a value recorded on
+  /// a tape, the gradient from the last backward pass over the tape.
+  pub fn grad(&self) -> T {
+    match &self.repr {
+      Repr::Node(node) => node.borrow().grad,
+      Repr::Tape(recording, index) => recording.borrow().grad(*index),
+    }
   }
 
-  pub fn set_grad(&mut self, grad: f64) {
-    self.inner.borrow_mut().grad = grad;
+  pub fn set_grad(&mut self, grad: T) {
+    let before = std::mem::replace(&mut self.node().borrow_mut().grad, grad);
+    anomaly::check_grad(self, before, grad, grad);
   }
 
   pub fn zero_grad(&mut self) {
-    self.inner.borrow_mut().grad = 0.0;
+    self.node().borrow_mut().grad = T::zero();
+  }
+
+  pub fn add_grad(&mut self, grad: T) {
+    let (before, after) = {
+      let mut inner = self.node().borrow_mut();
+      let before = inner.grad;
+      inner.grad = before + grad;
+      (before, inner.grad)
+    };
+    anomaly::check_grad(self, before, grad, after);
+  }
+
+  pub fn requires_grad(&self) -> bool {
+    match &self.repr {
+      Repr::Node(node) => node.borrow().requires_grad,
+      // Only ops on inputs that require a gradient are recorded.
+      Repr::Tape(..) => true,
+    }
   }
 
-  pub fn add_grad(&mut self, grad: f64) {
-    self.inner.borrow_mut().grad += grad;
+  pub fn set_requires_grad(&mut self, requires_grad: bool) {
+    self.node().borrow_mut().requires_grad = requires_grad;
   }
 
   pub fn label(&self) -> String {
-    self.inner.borrow().label.to_string()
+    self.node().borrow().label.to_string()
   }
 
   pub fn set_label(&mut self, label: &str) {
-    self.inner.borrow_mut().label = label.to_string();
+    self.node().borrow_mut().label = label.to_string();
   }
 
   pub fn op(&self) -> Option<String> {
-    self.inner.borrow().op.clone()
+    self.node().borrow().op.clone()
   }
 
   pub(crate) fn set_op(&self, op: Option<&str>) {
-    self.inner.borrow_mut().op = op.map(|x| x.to_string());
+    self.node().borrow_mut().op = op.map(|x| x.to_string());
   }
 
-  pub fn prev(&self) -> Vec<Value> {
-    self.inner.borrow().prev.clone()
+  /// What this node computes, if it is a built-in op whose functions
+  /// weren't replaced.
+  pub(crate) fn kind(&self) -> Option<OpKind> {
+    self.node().borrow().kind.clone()
   }
 
-  pub(crate) fn add_prev(&mut self, prev: &[&Value]) {
+  pub(crate) fn set_kind(&mut self, kind: Option<OpKind>) {
+    self.node().borrow_mut().kind = kind;
+  }
+
+  pub fn prev(&self) -> Vec<Value<T>> {
+    self.node().borrow().prev.clone()
+  }
+
+  pub(crate) fn add_prev(&mut self, prev: &[&Value<T>]) {
     for &v in prev {
-      self.inner.borrow_mut().prev.push(v.clone());
+      self.node().borrow_mut().prev.push(v.clone());
     }
   }
 
+  /// Runs the hooks on this node's gradient, which must be final, and then
+  /// propagates it to the inputs.
   pub fn invoke_grad_fn(&mut self) {
-    let grad = self.grad();
-    if let Some(grad_fn) = self.inner.borrow_mut().grad_fn.as_mut() {
-      grad_fn(grad);
+    self.propagate_grad(|_, _| {});
+  }
+
+  /// Like [`Value::invoke_grad_fn`], passing this node and the gradients of
+  /// its inputs to `check` before they are added to the inputs.
+  pub(crate) fn propagate_grad(&mut self, check: impl FnOnce(&Self, &[T])) {
+    self.run_hooks();
+    let input_grads = self.input_grads(self.grad());
+    check(self, &input_grads);
+    for (mut input, &grad) in self.prev().into_iter().zip(&input_grads) {
+      if input.requires_grad() {
+        input.add_grad(grad);
+      }
     }
   }
 
-  pub fn set_grad_fn(&mut self, grad_fn: Box<dyn FnMut(f64)>) {
-    self.inner.borrow_mut().grad_fn = Some(Box::new(grad_fn));
+  /// Returns the gradients this node would pass to each of its inputs for the
+  /// upstream gradient `grad`, without writing them anywhere.
+  pub(crate) fn input_grads(&self, grad: T) -> Vec<T> {
+    let (grad_fn, inputs, data) = {
+      let inner = self.node().borrow();
+      let Some(grad_fn) = inner.grad_fn.clone() else {
+        return vec![];
+      };
+      let inputs = inner.prev.iter().map(|v| v.data()).collect::<Vec<_>>();
+      (grad_fn, inputs, inner.data)
+    };
+    grad_fn(&inputs, data, grad)
+  }
+
+  /// Re-evaluates an op node from the current data of its inputs. Leaves
+  /// keep their data.
+  pub(crate) fn recompute(&mut self) {
+    let (forward_fn, inputs) = {
+      let inner = self.node().borrow();
+      let Some(forward_fn) = inner.forward_fn.clone() else {
+        return;
+      };
+      let inputs = inner.prev.iter().map(|v| v.data()).collect::<Vec<_>>();
+      (forward_fn, inputs)
+    };
+    self.set_data(forward_fn(&inputs));
+    if anomaly::is_detecting() {
+      let prev = self.prev();
+      let op = self.op().unwrap_or_default();
+      anomaly::check_forward(self, &op, &prev.iter().collect::<Vec<_>>());
+    }
+  }
+
+  /// Replaces the function that computes the gradients of this node's
+  /// inputs. It returns the gradients instead of adding them to the inputs
+  /// itself, so that [`grad`] can compute them without side effects; the
+  /// `FnMut(f64)` callbacks taken before that have to be rewritten to return
+  /// one gradient per input.
+  ///
+  /// A node with a replaced gradient function is never merged with another
+  /// by [`CompiledGraph::optimize`].
+  pub fn set_grad_fn(&mut self, grad_fn: GradFn<T>) {
+    let mut inner = self.node().borrow_mut();
+    inner.grad_fn = Some(Shared::from(grad_fn));
+    inner.kind = None;
+  }
+
+  /// Returns whether the graph behind this node was freed by
+  /// [`Value::backward`].
+  pub fn is_released(&self) -> bool {
+    self.node().borrow().released
+  }
+
+  /// Drops the inputs and gradient functions of an op node once its gradient
+  /// has been propagated. Leaves are left untouched so they can be reused.
+  pub(crate) fn release(&mut self) {
+    let mut inner = self.node().borrow_mut();
+    if inner.prev.is_empty() {
+      return;
+    }
+    inner.released = true;
+    inner.forward_fn = None;
+    inner.grad_fn = None;
+    inner.grad_graph_fn = None;
+    inner.hooks.clear();
+    inner.prev.clear();
+  }
+
+  /// Replaces the function that builds the gradients of this node's inputs
+  /// as a graph. As with [`Value::set_grad_fn`], the node is then never
+  /// merged by [`CompiledGraph::optimize`].
+  pub fn set_grad_graph_fn(&mut self, grad_graph_fn: GradGraphFn<T>) {
+    let mut inner = self.node().borrow_mut();
+    inner.grad_graph_fn = Some(Shared::from(grad_graph_fn));
+    inner.kind = None;
+  }
+
+  pub(crate) fn invoke_grad_graph_fn(&self, grad: &Value<T>) -> Vec<Value<T>> {
+    // The function may read this node, so it is called after the borrow ends.
+    let (grad_graph_fn, prev) = {
+      let inner = self.node().borrow();
+      let Some(grad_graph_fn) = inner.grad_graph_fn.clone() else {
+        panic!(
+          "op {:?} does not support gradients with a graph",
+          inner.op.as_deref().unwrap_or_default()
+        );
+      };
+      (grad_graph_fn, inner.prev.clone())
+    };
+    grad_graph_fn(&prev, self, grad)
+  }
+}
+
+impl<T: Scalar> Drop for ValueInner<T> {
+  /// Tears the graph down with an explicit work stack. The default drop glue
+  /// would recurse once per level through `prev`, which overflows the stack
+  /// on long chains.
+  fn drop(&mut self) {
+    self.forward_fn.take();
+    self.grad_fn.take();
+    self.grad_graph_fn.take();
+    let mut stack = std::mem::take(&mut self.prev);
+
+    while let Some(value) = stack.pop() {
+      let Repr::Node(node) = value.repr else {
+        continue;
+      };
+      if let Ok(inner) = Shared::try_unwrap(node) {
+        let mut inner = inner.into_inner();
+        inner.grad_fn.take();
+        stack.append(&mut inner.prev);
+      }
+    }
   }
 }
 
-impl fmt::Debug for Value {
+impl<T: Scalar> fmt::Debug for Value<T> {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    let inner = self.inner.borrow();
+    let Repr::Node(node) = &self.repr else {
+      return f
+        .debug_struct("Value")
+        .field("data", &self.data())
+        .field("grad", &self.grad())
+        .finish();
+    };
+    let inner = node.borrow();
     let mut w = f.debug_struct("Value");
     w.field("data", &inner.data).field("grad", &inner.grad);
 
@@ -134,29 +472,36 @@ impl fmt::Debug for Value {
   }
 }
 
-impl Eq for Value {}
+impl<T: Scalar> Eq for Value<T> {}
 
-impl PartialEq for Value {
+impl<T: Scalar> PartialEq for Value<T> {
   fn eq(&self, other: &Self) -> bool {
-    Rc::ptr_eq(&self.inner, &other.inner)
+    match (&self.repr, &other.repr) {
+      (Repr::Node(a), Repr::Node(b)) => Shared::ptr_eq(a, b),
+      (Repr::Tape(a, i), Repr::Tape(b, j)) => Shared::ptr_eq(a, b) && i == j,
+      _ => false,
+    }
   }
 }
 
-impl Eq for ValueInner {}
+impl<T: Scalar> Eq for ValueInner<T> {}
 
-impl PartialEq for ValueInner {
+impl<T: Scalar> PartialEq for ValueInner<T> {
   fn eq(&self, other: &Self) -> bool {
     self.id == other.id
   }
 }
 
-impl Hash for Value {
+impl<T: Scalar> Hash for Value<T> {
   fn hash<H: Hasher>(&self, state: &mut H) {
-    self.inner.borrow().id.hash(state);
+    match &self.repr {
+      Repr::Node(node) => node.borrow().id.hash(state),
+      Repr::Tape(_, index) => index.hash(state),
+    }
   }
 }
 
-impl Hash for ValueInner {
+impl<T: Scalar> Hash for ValueInner<T> {
   fn hash<H: Hasher>(&self, state: &mut H) {
     self.id.hash(state);
   }
@@ -174,12 +519,35 @@ mod tests {
     let h = (&z * &z).relu();
     let mut y = &h + &q + &q * &x;
 
-    y.backward();
+    y.backward().unwrap();
 
     assert_eq!(-20.0, y.data());
     assert_eq!(46.0, x.grad());
   }
 
+  #[test]
+  fn test_example_1_f32() {
+    let x = Value::new(-4.0f32, None);
+    let z: Value<f32> = 2.0 * &x + 2.0 + &x;
+    let q = z.relu() + &z * &x;
+    let h = (&z * &z).relu();
+    let mut y = &h + &q + &q * &x;
+
+    y.backward().unwrap();
+
+    assert_eq!(-20.0, y.data());
+    assert_eq!(46.0, x.grad());
+    assert_eq!(grad(&(&x * &x * &x), &[x.clone()]), vec![48.0f32]);
+  }
+
+  #[test]
+  fn test_value_is_send_and_sync() {
+    fn assert_send_sync<S: Send + Sync>() {}
+    assert_send_sync::<Value>();
+    assert_send_sync::<Value<f32>>();
+    assert_send_sync::<crate::MLP>();
+  }
+
   #[test]
   fn test_debug() {
     let mut v = Value::new(123.456, Some("abcd"));
@@ -0,0 +1,87 @@
+use std::borrow::Cow;
+
+use crate::Scalar;
+
+/// What a built-in op node computes: the op and the bit patterns of the
+/// parameters captured by its functions. Two nodes with the same kind and
+/// the same inputs compute the same value and gradients, which is what
+/// [`crate::CompiledGraph::optimize`] relies on to merge them.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct OpKind {
+  name: &'static str,
+  params: Vec<u64>,
+}
+
+impl OpKind {
+  pub(crate) fn name(&self) -> &'static str {
+    self.name
+  }
+
+  pub(crate) fn params(&self) -> &[u64] {
+    &self.params
+  }
+
+  /// The kind of the op spliced from this one with some inputs fixed to
+  /// constants, given the bit patterns of the constants in their slots.
+  pub(crate) fn with_fixed(&self, fixed: &[Option<u64>]) -> OpKind {
+    let mut params = self.params.clone();
+    for slot in fixed {
+      params.extend(match slot {
+        Some(bits) => [1, *bits],
+        None => [0, 0],
+      });
+    }
+    OpKind {
+      name: self.name,
+      params,
+    }
+  }
+}
+
+/// The label an op node is created with and, unless its functions are
+/// opaque to the engine, its [`OpKind`]. A static label converts into an op
+/// without parameters that is identified by the label itself.
+pub(crate) struct OpInfo {
+  pub(crate) label: Cow<'static, str>,
+  pub(crate) kind: Option<OpKind>,
+}
+
+impl OpInfo {
+  /// A built-in op labelled `label` whose functions capture `params`.
+  pub(crate) fn with_params(
+    label: String,
+    name: &'static str,
+    params: Vec<u64>,
+  ) -> Self {
+    Self {
+      label: label.into(),
+      kind: Some(OpKind { name, params }),
+    }
+  }
+
+  /// An op whose functions the engine can't compare, like the ones supplied
+  /// by users. Such nodes are never merged.
+  pub(crate) fn opaque(label: String) -> Self {
+    Self {
+      label: label.into(),
+      kind: None,
+    }
+  }
+}
+
+impl From<&'static str> for OpInfo {
+  fn from(label: &'static str) -> Self {
+    OpInfo {
+      label: label.into(),
+      kind: Some(OpKind {
+        name: label,
+        params: vec![],
+      }),
+    }
+  }
+}
+
+/// The bit pattern of a scalar parameter or constant, through `f64`.
+pub(crate) fn bits<T: Scalar>(x: T) -> u64 {
+  x.to_f64().map_or(u64::MAX, f64::to_bits)
+}
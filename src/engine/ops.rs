@@ -1,38 +1,38 @@
 use std::ops::*;
 
-use crate::Value;
+use crate::{Scalar, Value};
+
+use super::op_kind::OpInfo;
 
 macro_rules! impl_op_ref {
   ($Op:ident, $op:ident, $OpAssign:ident, $op_assign:ident) => {
-    impl $Op<&Value> for Value {
-      type Output = Value;
+    impl<T: Scalar> $Op<&Value<T>> for Value<T> {
+      type Output = Value<T>;
 
-      fn $op(self, rhs: &Value) -> Self::Output {
+      fn $op(self, rhs: &Value<T>) -> Self::Output {
         self.$op(rhs.clone())
       }
     }
 
-    impl $Op<Value> for &Value {
-      type Output = Value;
+    impl<T: Scalar> $Op<Value<T>> for &Value<T> {
+      type Output = Value<T>;
 
-      fn $op(self, rhs: Value) -> Self::Output {
+      fn $op(self, rhs: Value<T>) -> Self::Output {
         self.clone().$op(rhs)
       }
     }
 
-    impl $Op<&Value> for &Value {
-      type Output = Value;
+    impl<T: Scalar> $Op<&Value<T>> for &Value<T> {
+      type Output = Value<T>;
 
-      fn $op(self, rhs: &Value) -> Self::Output {
+      fn $op(self, rhs: &Value<T>) -> Self::Output {
         self.clone().$op(rhs.clone())
       }
     }
 
-    impl $OpAssign<&Value> for Value {
-      fn $op_assign(&mut self, rhs: &Value) {
-        let result = self.clone().$op(rhs.clone());
-        let result = result.inner();
-        self.set_inner(result);
+    impl<T: Scalar> $OpAssign<&Value<T>> for Value<T> {
+      fn $op_assign(&mut self, rhs: &Value<T>) {
+        *self = self.clone().$op(rhs.clone());
       }
     }
   };
@@ -40,39 +40,37 @@ macro_rules! impl_op_ref {
 
 macro_rules! impl_op_num {
   ($Op:ident, $op:ident, $OpAssign:ident, $op_assign:ident, $ty:ty) => {
-    impl $Op<$ty> for Value {
-      type Output = Value;
+    impl<T: Scalar> $Op<$ty> for Value<T> {
+      type Output = Value<T>;
       fn $op(self, rhs: $ty) -> Self::Output {
-        self.$op(Value::new(rhs as f64, None))
+        self.$op(Value::constant(T::lit(rhs as f64)))
       }
     }
 
-    impl $Op<$ty> for &Value {
-      type Output = Value;
+    impl<T: Scalar> $Op<$ty> for &Value<T> {
+      type Output = Value<T>;
       fn $op(self, rhs: $ty) -> Self::Output {
         self.clone().$op(rhs)
       }
     }
 
-    impl $Op<Value> for $ty {
-      type Output = Value;
-      fn $op(self, rhs: Value) -> Self::Output {
-        Value::new(self as f64, None).$op(rhs)
+    impl<T: Scalar> $Op<Value<T>> for $ty {
+      type Output = Value<T>;
+      fn $op(self, rhs: Value<T>) -> Self::Output {
+        Value::constant(T::lit(self as f64)).$op(rhs)
       }
     }
 
-    impl $Op<&Value> for $ty {
-      type Output = Value;
-      fn $op(self, rhs: &Value) -> Self::Output {
+    impl<T: Scalar> $Op<&Value<T>> for $ty {
+      type Output = Value<T>;
+      fn $op(self, rhs: &Value<T>) -> Self::Output {
         self.$op(rhs.clone())
       }
     }
 
-    impl $OpAssign<$ty> for Value {
+    impl<T: Scalar> $OpAssign<$ty> for Value<T> {
       fn $op_assign(&mut self, rhs: $ty) {
-        let result = self.clone().$op(rhs);
-        let result = result.inner();
-        self.set_inner(result);
+        *self = self.clone().$op(rhs);
       }
     }
   };
@@ -80,9 +78,9 @@ macro_rules! impl_op_num {
 
 macro_rules! impl_num {
   ($ty:ty) => {
-    impl From<$ty> for Value {
+    impl<T: Scalar> From<$ty> for Value<T> {
       fn from(value: $ty) -> Self {
-        Value::new(value as f64, None)
+        Value::new(T::lit(value as f64), None)
       }
     }
   };
@@ -102,128 +100,99 @@ macro_rules! impl_op {
     impl_op_num!($Op, $op, $OpAssign, $op_assign, i64);
     impl_op_num!($Op, $op, $OpAssign, $op_assign, u64);
 
-    impl $OpAssign<Value> for Value {
-      fn $op_assign(&mut self, rhs: Value) {
-        let result = self.clone().$op(rhs);
-        let result = result.inner();
-        self.set_inner(result);
+    impl<T: Scalar> $OpAssign<Value<T>> for Value<T> {
+      fn $op_assign(&mut self, rhs: Value<T>) {
+        *self = self.clone().$op(rhs);
       }
     }
   };
 }
 
-impl Add<Value> for Value {
-  type Output = Value;
-
-  fn add(self, rhs: Value) -> Self::Output {
-    let a = self.data();
-    let b = rhs.data();
-    let mut result = Value::new(a + b, None);
-    result.set_op(Some("+"));
-    result.add_prev(&[&self, &rhs]);
-
-    let mut tmp_rhs = rhs.clone();
-    let mut tmp_self = self.clone();
-
-    result.set_grad_fn(Box::new(move |grad| {
-      tmp_self.add_grad(grad);
-      tmp_rhs.add_grad(grad);
-    }));
-
-    result
+impl<T: Scalar> Add<Value<T>> for Value<T> {
+  type Output = Value<T>;
+
+  fn add(self, rhs: Value<T>) -> Self::Output {
+    Value::from_op(
+      |inputs| inputs[0] + inputs[1],
+      "+",
+      &[&self, &rhs],
+      |_, _, grad| vec![grad, grad],
+      |_, _, grad| vec![grad.clone(), grad.clone()],
+    )
   }
 }
 
-impl Sub<Value> for Value {
-  type Output = Value;
-
-  fn sub(self, rhs: Value) -> Self::Output {
-    let a = self.data();
-    let b = rhs.data();
-    let mut result = Value::new(a - b, None);
-    result.set_op(Some("-"));
-    result.add_prev(&[&self, &rhs]);
-
-    let mut tmp_self = self.clone();
-    let mut tmp_rhs = rhs.clone();
-
-    result.set_grad_fn(Box::new(move |grad| {
-      tmp_self.add_grad(grad);
-      tmp_rhs.add_grad(-grad);
-    }));
-
-    result
+impl<T: Scalar> Sub<Value<T>> for Value<T> {
+  type Output = Value<T>;
+
+  fn sub(self, rhs: Value<T>) -> Self::Output {
+    Value::from_op(
+      |inputs| inputs[0] - inputs[1],
+      "-",
+      &[&self, &rhs],
+      |_, _, grad| vec![grad, -grad],
+      |_, _, grad| vec![grad.clone(), -grad],
+    )
   }
 }
 
-impl Neg for Value {
-  type Output = Value;
+impl<T: Scalar> Neg for Value<T> {
+  type Output = Value<T>;
 
   fn neg(self) -> Self::Output {
-    let mut result = Value::new(-self.data(), None);
-    result.set_op(Some("-"));
-    result.add_prev(&[&self]);
-
-    let mut tmp_self = self.clone();
-
-    result.set_grad_fn(Box::new(move |grad| {
-      tmp_self.add_grad(-grad);
-    }));
-
-    result
+    Value::from_op(
+      |inputs| -inputs[0],
+      OpInfo::with_params("-".to_string(), "neg", vec![]),
+      &[&self],
+      |_, _, grad| vec![-grad],
+      |_, _, grad| vec![-grad],
+    )
   }
 }
 
-impl Neg for &Value {
-  type Output = Value;
+impl<T: Scalar> Neg for &Value<T> {
+  type Output = Value<T>;
 
   fn neg(self) -> Self::Output {
     self.clone().neg()
   }
 }
 
-impl Mul<Value> for Value {
-  type Output = Value;
-
-  fn mul(self, rhs: Value) -> Self::Output {
-    let a = self.data();
-    let b = rhs.data();
-    let mut result = Value::new(a * b, None);
-    result.set_op(Some("*"));
-    result.add_prev(&[&self, &rhs]);
-
-    let mut tmp_self = self.clone();
-    let mut tmp_rhs = rhs.clone();
-
-    result.set_grad_fn(Box::new(move |grad| {
-      tmp_self.add_grad(grad * b);
-      tmp_rhs.add_grad(grad * a);
-    }));
-
-    result
+impl<T: Scalar> Mul<Value<T>> for Value<T> {
+  type Output = Value<T>;
+
+  fn mul(self, rhs: Value<T>) -> Self::Output {
+    Value::from_op(
+      |inputs| inputs[0] * inputs[1],
+      "*",
+      &[&self, &rhs],
+      |inputs, _, grad| {
+        let (a, b) = (inputs[0], inputs[1]);
+        vec![grad * b, grad * a]
+      },
+      |prev, _, grad| vec![grad * &prev[1], grad * &prev[0]],
+    )
   }
 }
 
-impl Div<Value> for Value {
-  type Output = Value;
-
-  fn div(self, rhs: Value) -> Self::Output {
-    let a = self.data();
-    let b = rhs.data();
-    let mut result = Value::new(a / b, None);
-    result.set_op(Some("/"));
-    result.add_prev(&[&self, &rhs]);
-
-    let mut tmp_rhs = rhs.clone();
-    let mut tmp_self = self.clone();
-
-    #[allow(clippy::suspicious_arithmetic_impl)]
-    result.set_grad_fn(Box::new(move |grad| {
-      tmp_self.add_grad(grad / b);
-      tmp_rhs.add_grad(-grad * a / (b * b));
-    }));
-
-    result
+impl<T: Scalar> Div<Value<T>> for Value<T> {
+  type Output = Value<T>;
+
+  #[allow(clippy::suspicious_arithmetic_impl)]
+  fn div(self, rhs: Value<T>) -> Self::Output {
+    Value::from_op(
+      |inputs| inputs[0] / inputs[1],
+      "/",
+      &[&self, &rhs],
+      |inputs, _, grad| {
+        let (a, b) = (inputs[0], inputs[1]);
+        vec![grad / b, -grad * a / (b * b)]
+      },
+      |prev, _, grad| {
+        let (a, b) = (&prev[0], &prev[1]);
+        vec![grad / b, -grad * a / (b * b)]
+      },
+    )
   }
 }
 
@@ -279,7 +248,7 @@ mod tests {
     let x: Value = 123.456.into();
     let y: Value = 456.789.into();
     let mut result = &x + &y;
-    result.backward();
+    result.backward().unwrap();
 
     assert_eq!(result.data(), 580.245);
     assert_eq!(result.grad(), 1.0);
@@ -294,7 +263,7 @@ mod tests {
 
     let mut result = &x - &y;
 
-    result.backward();
+    result.backward().unwrap();
 
     assert_eq!(result.data(), -333.33299999999997);
 
@@ -309,7 +278,7 @@ mod tests {
     let x: Value = 123.456.into();
     let mut result = -&x;
 
-    result.backward();
+    result.backward().unwrap();
 
     assert_eq!(result.data(), -123.456);
     assert_eq!(result.grad(), 1.0);
@@ -323,7 +292,7 @@ mod tests {
 
     let mut result = &x * &y;
 
-    result.backward();
+    result.backward().unwrap();
 
     assert_eq!(result.data(), 56393.342784);
     assert_eq!(result.grad(), 1.0);
@@ -338,7 +307,7 @@ mod tests {
 
     let mut result = &x / &y;
 
-    result.backward();
+    result.backward().unwrap();
 
     assert_eq!(result.data(), 0.270269205256694);
     assert_eq!(result.grad(), 1.0);
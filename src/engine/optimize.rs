@@ -0,0 +1,354 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{CompiledGraph, Scalar, Value};
+
+use super::op_kind::bits;
+use super::shared::Shared;
+
+/// An input of a node as seen by common subexpression elimination: another
+/// node, or a constant compared by value.
+#[derive(PartialEq, Eq, Hash)]
+enum Slot {
+  Node(u32),
+  Constant(u64),
+}
+
+impl<T: Scalar> CompiledGraph<T> {
+  /// Returns an equivalent graph with fewer nodes, which computes the same
+  /// outputs and the same gradients. It applies three passes:
+  ///
+  /// - constant folding: op nodes whose inputs are all constants become
+  ///   constants, and the constant inputs of the other ops, such as the
+  ///   literals wrapped by the numeric operator overloads, are folded into
+  ///   the op itself;
+  /// - common subexpression elimination: nodes of the same built-in op,
+  ///   with the same parameters and the same inputs, are merged. Custom ops,
+  ///   checkpointed segments and nodes whose gradient functions were
+  ///   replaced are never merged;
+  /// - dead code elimination: only the nodes that feed an output are kept.
+  ///
+  /// Constants are the leaves that don't require a gradient and aren't
+  /// inputs of the graph. Their data is taken as fixed from here on. The new
+  /// graph has the same leaves, so gradients still accumulate in the same
+  /// parameters, and it shares the nodes that didn't change with this one.
+  #[allow(clippy::mutable_key_type)]
+  pub fn optimize(&self) -> CompiledGraph<T> {
+    let inputs = self.inputs().iter().collect::<HashSet<_>>();
+    let is_constant = |v: &Value<T>| {
+      v.prev().is_empty() && !v.requires_grad() && !inputs.contains(v)
+    };
+    let rewrite = |rewritten: &HashMap<Value<T>, Value<T>>, v: &Value<T>| {
+      rewritten.get(v).cloned().unwrap_or_else(|| v.clone())
+    };
+
+    let mut rewritten = HashMap::new();
+    let mut merged = HashMap::new();
+    for node in self.nodes() {
+      let prev = node
+        .prev()
+        .iter()
+        .map(|v| rewrite(&rewritten, v))
+        .collect::<Vec<_>>();
+      let constants = prev.iter().map(is_constant).collect::<Vec<_>>();
+
+      let result = if constants.iter().all(|&c| c) {
+        Value::constant(node.data())
+      } else if let Some(kind) = node.kind() {
+        let slots = prev
+          .iter()
+          .zip(&constants)
+          .map(|(v, &c)| {
+            if c {
+              Slot::Constant(bits(v.data()))
+            } else {
+              Slot::Node(v.id())
+            }
+          })
+          .collect::<Vec<_>>();
+        merged
+          .entry((kind, slots))
+          .or_insert_with(|| splice(node, &prev, &constants))
+          .clone()
+      } else {
+        splice(node, &prev, &constants)
+      };
+      rewritten.insert(node.clone(), result);
+    }
+
+    let outputs = self
+      .outputs()
+      .iter()
+      .map(|v| rewrite(&rewritten, v))
+      .collect::<Vec<_>>();
+    CompiledGraph::new(self.inputs(), &outputs)
+  }
+}
+
+/// Rebuilds `node` over the rewritten inputs `prev`. The inputs flagged in
+/// `constants` are left out of the graph and their data is passed to the
+/// node's functions directly. Returns `node` itself if nothing changed.
+fn splice<T: Scalar>(
+  node: &Value<T>,
+  prev: &[Value<T>],
+  constants: &[bool],
+) -> Value<T> {
+  if !constants.contains(&true) && prev == node.prev() {
+    return node.clone();
+  }
+
+  let (forward_fn, grad_fn, grad_graph_fn) = {
+    let inner = node.node().borrow();
+    (
+      inner
+        .forward_fn
+        .clone()
+        .expect("op nodes have a forward function"),
+      inner
+        .grad_fn
+        .clone()
+        .expect("op nodes have a gradient function"),
+      inner.grad_graph_fn.clone(),
+    )
+  };
+  let fixed = prev
+    .iter()
+    .zip(constants)
+    .map(|(v, &c)| c.then(|| v.clone()))
+    .collect::<Vec<_>>();
+  let fixed_data = fixed
+    .iter()
+    .map(|v| v.as_ref().map(Value::data))
+    .collect::<Vec<_>>();
+  let keep = constants.iter().map(|&c| !c).collect::<Vec<_>>();
+  let graph_keep = keep.clone();
+  let forward_data = fixed_data.clone();
+
+  let mut result = Value::new(node.data(), None);
+  result.set_op(node.op().as_deref());
+  let fixed_bits = fixed_data.iter().map(|c| c.map(bits)).collect::<Vec<_>>();
+  result.set_kind(node.kind().map(|kind| kind.with_fixed(&fixed_bits)));
+  let kept = prev.iter().zip(constants).filter(|&(_, &c)| !c);
+  result.add_prev(&kept.map(|(v, _)| v).collect::<Vec<_>>());
+  {
+    let mut inner = result.node().borrow_mut();
+    inner.forward_fn = Some(Shared::new(move |inputs: &[T]| {
+      forward_fn(&merge(&forward_data, inputs))
+    }));
+    inner.grad_fn = Some(Shared::new(move |inputs: &[T], out, grad| {
+      let grads = grad_fn(&merge(&fixed_data, inputs), out, grad);
+      select(grads, &keep)
+    }));
+    inner.grad_graph_fn = grad_graph_fn.map(|grad_graph_fn| {
+      Shared::new(move |prev: &[Value<T>], out: &Value<T>, grad: &Value<T>| {
+        let grads = grad_graph_fn(&merge(&fixed, prev), out, grad);
+        select(grads, &graph_keep)
+      }) as Shared<_>
+    });
+  }
+  result
+}
+
+/// Fills the `None` slots of `fixed` with `inputs`, in order.
+fn merge<X: Clone>(fixed: &[Option<X>], inputs: &[X]) -> Vec<X> {
+  let mut inputs = inputs.iter();
+  fixed
+    .iter()
+    .map(|c| match c {
+      Some(c) => c.clone(),
+      None => inputs.next().expect("too few inputs").clone(),
+    })
+    .collect()
+}
+
+/// Keeps the gradients of the inputs flagged in `keep`.
+fn select<X>(grads: Vec<X>, keep: &[bool]) -> Vec<X> {
+  grads
+    .into_iter()
+    .zip(keep)
+    .filter_map(|(g, &k)| k.then_some(g))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{CustomOp, MLP, Module, grad_graph};
+  use rand::{SeedableRng, rngs::StdRng};
+
+  /// Runs both graphs on `data` and checks that they agree on the outputs
+  /// and on the gradients of `wrt`.
+  fn assert_equivalent(
+    a: &mut CompiledGraph,
+    b: &mut CompiledGraph,
+    wrt: &[Value],
+    data: &[f64],
+  ) {
+    let grads = |graph: &mut CompiledGraph| {
+      let outputs = graph.run(data).unwrap();
+      for mut v in wrt.iter().cloned() {
+        v.zero_grad();
+      }
+      graph.backward().unwrap();
+      (outputs, wrt.iter().map(Value::grad).collect::<Vec<_>>())
+    };
+    let (outputs_a, grads_a) = grads(a);
+    let (outputs_b, grads_b) = grads(b);
+    assert_eq!(outputs_a, outputs_b);
+    for (ga, gb) in grads_a.into_iter().zip(grads_b) {
+      assert!((ga - gb).abs() < 1e-12, "{} != {}", ga, gb);
+    }
+  }
+
+  #[test]
+  fn test_folds_literals() {
+    let x = Value::placeholder("x");
+    let y: Value = &x * 2 + 1;
+    let mut graph = CompiledGraph::new(&[x.clone()], &[y]);
+    let mut optimized = graph.optimize();
+
+    assert_eq!(graph.num_nodes(), 5);
+    assert_eq!(optimized.num_nodes(), 3);
+    for data in [-1.5, 0.0, 4.0] {
+      assert_equivalent(&mut graph, &mut optimized, &[x.clone()], &[data]);
+    }
+  }
+
+  #[test]
+  fn test_folds_constant_subgraphs() {
+    let x: Value = Value::placeholder("x");
+    let mut w = Value::new(0.5, Some("w"));
+    let y = (w.exp() * 3.0f64).ln() * &x;
+    let mut graph = CompiledGraph::new(&[x.clone()], &[y]);
+
+    // Freezing `w` after tracing leaves a subgraph with only constants.
+    w.set_requires_grad(false);
+    let mut optimized = graph.optimize();
+
+    assert_eq!(graph.num_nodes(), 7);
+    assert_eq!(optimized.num_nodes(), 2);
+    assert_equivalent(&mut graph, &mut optimized, &[x.clone()], &[2.0]);
+  }
+
+  #[test]
+  fn test_merges_common_subexpressions() {
+    let x = Value::placeholder("x");
+    let y = Value::placeholder("y");
+    let a: Value = (&x * &y).tanh() * 2;
+    let b: Value = (&x * &y).tanh() * 2;
+    let z = &a + &b + (&x * &y);
+    let mut graph = CompiledGraph::new(&[x.clone(), y.clone()], &[z]);
+    let mut optimized = graph.optimize();
+
+    assert_eq!(graph.num_nodes(), 13);
+    assert_eq!(optimized.num_nodes(), 7);
+    let wrt = [x.clone(), y.clone()];
+    assert_equivalent(&mut graph, &mut optimized, &wrt, &[0.3, -1.2]);
+  }
+
+  #[test]
+  fn test_keeps_distinct_ops() {
+    let x = Value::placeholder("x");
+    let y = Value::placeholder("y");
+    let z = Value::select(true, &x, &y) + Value::select(false, &x, &y);
+    let a: Value = &x * 2 + &x * 3;
+    let mut graph = CompiledGraph::new(&[x.clone(), y.clone()], &[z, a]);
+    let mut optimized = graph.optimize();
+
+    assert_eq!(optimized.num_nodes(), graph.num_nodes() - 2);
+    let wrt = [x.clone(), y.clone()];
+    assert_equivalent(&mut graph, &mut optimized, &wrt, &[1.0, 2.0]);
+  }
+
+  #[test]
+  fn test_never_merges_opaque_nodes() {
+    struct Exp;
+    struct Sin;
+    impl CustomOp for Exp {
+      fn name(&self) -> String {
+        "f".to_string()
+      }
+      fn forward(&self, inputs: &[f64]) -> f64 {
+        inputs[0].exp()
+      }
+      fn backward(&self, _: &[f64], out: f64, grad: f64) -> Vec<f64> {
+        vec![grad * out]
+      }
+    }
+    impl CustomOp for Sin {
+      fn name(&self) -> String {
+        "f".to_string()
+      }
+      fn forward(&self, inputs: &[f64]) -> f64 {
+        inputs[0].sin()
+      }
+      fn backward(&self, inputs: &[f64], _: f64, grad: f64) -> Vec<f64> {
+        vec![grad * inputs[0].cos()]
+      }
+    }
+
+    let x = Value::placeholder("x");
+    let mut scaled: Value = &x * 2;
+    scaled.set_grad_fn(Box::new(|_, _, grad| vec![10.0 * grad, 0.0]));
+    let outputs = [
+      Value::apply(Exp, &[&x]) + Value::apply(Sin, &[&x]),
+      scaled + &x * 2,
+    ];
+    let mut graph = CompiledGraph::new(&[x.clone()], &outputs);
+    let mut optimized = graph.optimize();
+
+    // Only the two literals, folded into the products, go away.
+    assert_eq!(optimized.num_nodes(), graph.num_nodes() - 2);
+    for data in [0.5, -1.0] {
+      assert_equivalent(&mut graph, &mut optimized, &[x.clone()], &[data]);
+    }
+  }
+
+  #[test]
+  fn test_optimize_twice() {
+    let x = Value::placeholder("x");
+    let y = Value::placeholder("y");
+    let two = Value::constant(2.0);
+    let a = Value::sum(&[x.clone(), two.clone()]) * Value::sum(&[x.clone()]);
+    let b = Value::sum(&[x.clone(), y.clone()]) + Value::sum(&[y.clone(), two]);
+    let mut graph = CompiledGraph::new(&[x.clone(), y.clone()], &[a, b]);
+    let once = graph.optimize();
+    let mut twice = once.optimize();
+
+    // Folding the constant into a sum must not make it equal to a sum of
+    // fewer inputs.
+    assert_eq!(twice.num_nodes(), once.num_nodes());
+    let wrt = [x.clone(), y.clone()];
+    assert_equivalent(&mut graph, &mut twice, &wrt, &[1.5, -0.5]);
+  }
+
+  #[test]
+  fn test_optimized_gradients_stay_differentiable() {
+    let x: Value = Value::placeholder("x");
+    let y = (&x * &x * 3.0f64).exp();
+    let mut graph = CompiledGraph::new(&[x.clone()], &[y]).optimize();
+    graph.run(&[0.5]).unwrap();
+
+    let dy = grad_graph(&graph.outputs()[0], &[x.clone()]).remove(0);
+    let d2y = grad_graph(&dy, &[x]).remove(0);
+    let y = 0.75f64.exp();
+    assert!((dy.data() - 3.0 * y).abs() < 1e-12);
+    assert!((d2y.data() - (6.0 + 9.0) * y).abs() < 1e-12);
+  }
+
+  #[test]
+  fn test_mlp() {
+    let mlp = MLP::new(3, &[4, 4, 1], &mut StdRng::from_seed([0u8; 32]));
+    let x = (0..3)
+      .map(|i| Value::placeholder(&format!("x{}", i)))
+      .collect::<Vec<_>>();
+    let y = mlp.call(&x.iter().collect::<Vec<_>>()).remove(0);
+    let loss: Value = (&y - 1) * (&y - 1) / 2;
+    let mut graph = CompiledGraph::new(&x, &[loss]);
+    let mut optimized = graph.optimize();
+
+    assert!(optimized.num_nodes() < graph.num_nodes());
+    let wrt = [x.clone(), mlp.parameters()].concat();
+    assert_equivalent(&mut graph, &mut optimized, &wrt, &[2.0, 3.0, -1.0]);
+    assert_equivalent(&mut graph, &mut optimized, &wrt, &[0.5, 1.0, 1.0]);
+  }
+}
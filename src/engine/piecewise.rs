@@ -0,0 +1,254 @@
+use crate::{Scalar, Value};
+
+use super::op_kind::OpInfo;
+use super::scalar::clamp;
+
+impl<T: Scalar> Value<T> {
+  /// Absolute value. The subgradient at zero is taken as 0.
+  pub fn abs(&self) -> Value<T> {
+    Value::from_op(
+      |inputs| inputs[0].abs(),
+      "abs",
+      &[self],
+      |inputs, _, grad| vec![grad * sign(inputs[0])],
+      |prev, _, grad| vec![grad * Value::constant(sign(prev[0].data()))],
+    )
+  }
+
+  /// The larger of `self` and `other`. The gradient goes to the larger input;
+  /// on a tie it is split evenly between both.
+  pub fn max(&self, other: &Value<T>) -> Value<T> {
+    Value::from_op(
+      |inputs| inputs[0].max(inputs[1]),
+      "max",
+      &[self, other],
+      |inputs, _, grad| {
+        let (a, b) = max_local_grads(inputs[0], inputs[1]);
+        vec![grad * a, grad * b]
+      },
+      |prev, _, grad| {
+        let (a, b) = max_local_grads(prev[0].data(), prev[1].data());
+        vec![grad * Value::constant(a), grad * Value::constant(b)]
+      },
+    )
+  }
+
+  /// The smaller of `self` and `other`. The gradient goes to the smaller
+  /// input; on a tie it is split evenly between both.
+  pub fn min(&self, other: &Value<T>) -> Value<T> {
+    Value::from_op(
+      |inputs| inputs[0].min(inputs[1]),
+      "min",
+      &[self, other],
+      |inputs, _, grad| {
+        let (a, b) = max_local_grads(inputs[1], inputs[0]);
+        vec![grad * a, grad * b]
+      },
+      |prev, _, grad| {
+        let (a, b) = max_local_grads(prev[1].data(), prev[0].data());
+        vec![grad * Value::constant(a), grad * Value::constant(b)]
+      },
+    )
+  }
+
+  /// Clamps `self` to `[lo, hi]`. The gradient is 1 inside the interval,
+  /// including on its bounds, and 0 outside it.
+  pub fn clamp(&self, lo: f64, hi: f64) -> Value<T> {
+    assert!(lo <= hi, "clamp: lo must not exceed hi");
+    let (lo_t, hi_t) = (T::lit(lo), T::lit(hi));
+    Value::from_op(
+      move |inputs| clamp(inputs[0], lo_t, hi_t),
+      OpInfo::with_params(
+        format!("clamp({}, {})", lo, hi),
+        "clamp",
+        vec![lo.to_bits(), hi.to_bits()],
+      ),
+      &[self],
+      move |inputs, _, grad| {
+        vec![grad * clamp_local_grad(inputs[0], lo_t, hi_t)]
+      },
+      move |prev, _, grad| {
+        let local_grad = clamp_local_grad(prev[0].data(), lo_t, hi_t);
+        vec![grad * Value::constant(local_grad)]
+      },
+    )
+  }
+
+  /// Returns `a` if `cond` holds and `b` otherwise. Both branches stay in the
+  /// graph, but the gradient only flows to the chosen one.
+  pub fn select(cond: bool, a: &Value<T>, b: &Value<T>) -> Value<T> {
+    Value::from_op(
+      move |inputs| if cond { inputs[0] } else { inputs[1] },
+      OpInfo::with_params("select".to_string(), "select", vec![cond as u64]),
+      &[a, b],
+      move |_, _, grad| {
+        if cond {
+          vec![grad, T::zero()]
+        } else {
+          vec![T::zero(), grad]
+        }
+      },
+      move |_, _, grad| {
+        let zero = Value::constant(T::zero());
+        if cond {
+          vec![grad.clone(), zero]
+        } else {
+          vec![zero, grad.clone()]
+        }
+      },
+    )
+  }
+}
+
+fn sign<T: Scalar>(x: T) -> T {
+  if x > T::zero() {
+    T::one()
+  } else if x < T::zero() {
+    -T::one()
+  } else {
+    T::zero()
+  }
+}
+
+/// Local gradients of `max(a, b)` with respect to `a` and `b`.
+fn max_local_grads<T: Scalar>(a: T, b: T) -> (T, T) {
+  if a > b {
+    (T::one(), T::zero())
+  } else if a < b {
+    (T::zero(), T::one())
+  } else {
+    (T::lit(0.5), T::lit(0.5))
+  }
+}
+
+/// The subgradient of clamping to `[lo, hi]`, shared with
+/// [`Value::hard_tanh`]: 1 inside the interval, including on its bounds, and
+/// 0 outside it.
+pub(crate) fn clamp_local_grad<T: Scalar>(x: T, lo: T, hi: T) -> T {
+  if x >= lo && x <= hi {
+    T::one()
+  } else {
+    T::zero()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::Value;
+  use crate::engine::gradcheck::assert_gradients;
+
+  #[test]
+  fn test_abs() {
+    for x in [-1.5, 0.7] {
+      assert_gradients(|v| v[0].abs(), &[x]);
+    }
+
+    let zero = Value::new(0.0, None);
+    zero.abs().backward().unwrap();
+    assert_eq!(zero.grad(), 0.0);
+
+    let x = Value::new(-3.0, None);
+    let mut y = x.abs();
+    y.backward().unwrap();
+    assert_eq!(y.data(), 3.0);
+    assert_eq!(x.grad(), -1.0);
+  }
+
+  #[test]
+  fn test_max_min() {
+    for (a, b) in [(1.5, -0.5), (-2.0, 0.3)] {
+      assert_gradients(|v| v[0].max(&v[1]), &[a, b]);
+      assert_gradients(|v| v[0].min(&v[1]), &[a, b]);
+    }
+
+    let a = Value::new(2.0, None);
+    let b = Value::new(5.0, None);
+    let mut y: Value = a.max(&b) + a.min(&b) * 10;
+    y.backward().unwrap();
+    assert_eq!(y.data(), 25.0);
+    assert_eq!(a.grad(), 10.0);
+    assert_eq!(b.grad(), 1.0);
+  }
+
+  #[test]
+  fn test_max_min_ties() {
+    let a = Value::new(1.0, None);
+    let b = Value::new(1.0, None);
+    a.max(&b).backward().unwrap();
+    assert_eq!((a.grad(), b.grad()), (0.5, 0.5));
+
+    let a = Value::new(1.0, None);
+    let b = Value::new(1.0, None);
+    a.min(&b).backward().unwrap();
+    assert_eq!((a.grad(), b.grad()), (0.5, 0.5));
+
+    let x = Value::new(4.0, None);
+    x.max(&x).backward().unwrap();
+    assert_eq!(x.grad(), 1.0);
+  }
+
+  #[test]
+  fn test_clamp() {
+    for x in [-2.0, 0.3, 2.0] {
+      assert_gradients(|v| v[0].clamp(-1.0, 1.0), &[x]);
+    }
+
+    let x = Value::new(1.0, None);
+    let mut y = x.clamp(-1.0, 1.0);
+    y.backward().unwrap();
+    assert_eq!(y.data(), 1.0);
+    assert_eq!(x.grad(), 1.0);
+    assert_eq!(y.op(), Some("clamp(-1, 1)".to_string()));
+
+    let x = Value::new(3.0, None);
+    x.clamp(-1.0, 1.0).backward().unwrap();
+    assert_eq!(x.grad(), 0.0);
+  }
+
+  #[test]
+  fn test_select() {
+    let a = Value::new(2.0, Some("a"));
+    let b = Value::new(-3.0, Some("b"));
+
+    let mut y: Value = Value::select(true, &a, &b) * 4;
+    y.backward().unwrap();
+    assert_eq!(y.data(), 8.0);
+    assert_eq!((a.grad(), b.grad()), (4.0, 0.0));
+
+    let mut y = Value::select(false, &a, &b);
+    y.backward().unwrap();
+    assert_eq!(y.data(), -3.0);
+    assert_eq!((a.grad(), b.grad()), (4.0, 1.0));
+
+    assert_gradients(
+      |v| Value::select(v[0].data() > 0.0, &v[0], &v[1]),
+      &[0.5, 1.5],
+    );
+  }
+
+  #[test]
+  fn test_hinge_loss() {
+    // sum_i max(0, 1 - y_i * score_i) with score = w * x
+    let w = Value::new(0.4, Some("w"));
+    let samples = [(1.0, 1.0), (-2.0, -1.0), (3.0, 1.0), (0.5, -1.0)];
+
+    let mut loss = Value::constant(0.0);
+    for (x, y) in samples {
+      let margin: Value = 1.0 - &w * x * y;
+      loss += Value::constant(0.0).max(&margin);
+    }
+    loss.backward().unwrap();
+
+    // Only the first, second and fourth samples are inside the margin.
+    assert!((loss.data() - (0.6 + 0.2 + 1.2)).abs() < 1e-12);
+    assert_eq!(w.grad(), -1.0 - 2.0 + 0.5);
+  }
+
+  #[test]
+  fn test_clamp_gradient_clipping() {
+    let x = Value::new(3.0, Some("x"));
+    let g = (&x * &x).clamp(-5.0, 5.0);
+    assert_eq!(g.data(), 5.0);
+    assert_gradients(|v| (&v[0] * &v[0]).clamp(-5.0, 5.0), &[1.5]);
+  }
+}
@@ -0,0 +1,251 @@
+use std::iter::{Product, Sum};
+
+use crate::{Scalar, Value};
+
+impl<T: Scalar> Value<T> {
+  /// Sums `values` in a single graph node instead of a chain of `+` nodes.
+  /// The empty sum is the constant 0.
+  pub fn sum(values: &[Value<T>]) -> Value<T> {
+    let prev = values.iter().collect::<Vec<_>>();
+    Value::from_op(
+      |inputs| sum(inputs),
+      "sum",
+      &prev,
+      |inputs, _, grad| vec![grad; inputs.len()],
+      |prev, _, grad| vec![grad.clone(); prev.len()],
+    )
+  }
+
+  /// Multiplies `values` in a single graph node. The empty product is the
+  /// constant 1. The gradient for each input is the product of the others,
+  /// so it stays exact when some inputs are zero.
+  pub fn product(values: &[Value<T>]) -> Value<T> {
+    let prev = values.iter().collect::<Vec<_>>();
+    Value::from_op(
+      |inputs| inputs.iter().fold(T::one(), |acc, &v| acc * v),
+      "product",
+      &prev,
+      |inputs, _, grad| {
+        products_of_others(inputs)
+          .into_iter()
+          .map(|p| grad * p)
+          .collect()
+      },
+      |prev, _, grad| {
+        (0..prev.len())
+          .map(|i| {
+            let others = prev
+              .iter()
+              .enumerate()
+              .filter(|&(j, _)| j != i)
+              .map(|(_, v)| v.clone())
+              .collect::<Vec<_>>();
+            grad * Value::product(&others)
+          })
+          .collect()
+      },
+    )
+  }
+
+  /// Arithmetic mean of `values` in a single graph node. The mean of an
+  /// empty slice is NaN.
+  pub fn mean(values: &[Value<T>]) -> Value<T> {
+    let prev = values.iter().collect::<Vec<_>>();
+    let n = T::lit(values.len() as f64);
+    Value::from_op(
+      move |inputs| sum(inputs) / n,
+      "mean",
+      &prev,
+      move |inputs, _, grad| vec![grad / n; inputs.len()],
+      move |prev, _, grad| vec![grad / Value::constant(n); prev.len()],
+    )
+  }
+
+  /// Dot product of `a` and `b` in a single graph node.
+  pub fn dot(a: &[Value<T>], b: &[Value<T>]) -> Value<T> {
+    assert_eq!(a.len(), b.len(), "dot: length mismatch");
+    let n = a.len();
+    let prev = a.iter().chain(b).collect::<Vec<_>>();
+    Value::from_op(
+      move |inputs| {
+        let (a, b) = inputs.split_at(n);
+        sum(&a.iter().zip(b).map(|(&a, &b)| a * b).collect::<Vec<_>>())
+      },
+      "dot",
+      &prev,
+      move |inputs, _, grad| {
+        let (a, b) = inputs.split_at(n);
+        let da = b.iter().map(|&b| grad * b);
+        let db = a.iter().map(|&a| grad * a);
+        da.chain(db).collect()
+      },
+      move |prev, _, grad| {
+        let (a, b) = prev.split_at(n);
+        let da = b.iter().map(|b| grad * b);
+        let db = a.iter().map(|a| grad * a);
+        da.chain(db).collect()
+      },
+    )
+  }
+}
+
+/// Left-to-right sum starting from the first element, so the result matches
+/// a chain of `+` nodes bit for bit.
+fn sum<T: Scalar>(values: &[T]) -> T {
+  match values.split_first() {
+    Some((&first, rest)) => rest.iter().fold(first, |acc, &v| acc + v),
+    None => T::zero(),
+  }
+}
+
+/// For each index, the product of every other element, computed from prefix
+/// and suffix products rather than by dividing the total.
+fn products_of_others<T: Scalar>(values: &[T]) -> Vec<T> {
+  let mut result = vec![T::one(); values.len()];
+  let mut prefix = T::one();
+  for (r, &v) in result.iter_mut().zip(values) {
+    *r = prefix;
+    prefix = prefix * v;
+  }
+  let mut suffix = T::one();
+  for (r, &v) in result.iter_mut().zip(values).rev() {
+    *r = *r * suffix;
+    suffix = suffix * v;
+  }
+  result
+}
+
+impl<T: Scalar> Sum<Value<T>> for Value<T> {
+  fn sum<I: Iterator<Item = Value<T>>>(iter: I) -> Self {
+    Value::sum(&iter.collect::<Vec<_>>())
+  }
+}
+
+impl<'a, T: Scalar> Sum<&'a Value<T>> for Value<T> {
+  fn sum<I: Iterator<Item = &'a Value<T>>>(iter: I) -> Self {
+    iter.cloned().sum()
+  }
+}
+
+impl<T: Scalar> Product<Value<T>> for Value<T> {
+  fn product<I: Iterator<Item = Value<T>>>(iter: I) -> Self {
+    Value::product(&iter.collect::<Vec<_>>())
+  }
+}
+
+impl<'a, T: Scalar> Product<&'a Value<T>> for Value<T> {
+  fn product<I: Iterator<Item = &'a Value<T>>>(iter: I) -> Self {
+    iter.cloned().product()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::Value;
+  use crate::engine::gradcheck::assert_gradients;
+
+  const INPUTS: [f64; 4] = [1.5, -0.5, 2.0, 0.75];
+
+  #[test]
+  fn test_sum() {
+    let xs = INPUTS
+      .iter()
+      .map(|&x| Value::new(x, None))
+      .collect::<Vec<_>>();
+    let mut y = Value::sum(&xs);
+    assert_eq!(y.prev().len(), 4);
+    y.backward().unwrap();
+    assert_eq!(y.data(), 3.75);
+    assert_eq!(y.op(), Some("sum".to_string()));
+    assert!(xs.iter().all(|x| x.grad() == 1.0));
+
+    assert_gradients(Value::sum, &INPUTS);
+    assert_gradients(|x| Value::sum(&[&x[0] * &x[1], x[2].tanh()]), &INPUTS);
+    assert_eq!(Value::<f64>::sum(&[]).data(), 0.0);
+  }
+
+  #[test]
+  fn test_sum_matches_chain() {
+    let values = [0.1, 0.2, 0.3, -0.7, 1e-17, 5.0];
+    let xs = values
+      .iter()
+      .map(|&x| Value::new(x, None))
+      .collect::<Vec<_>>();
+    let mut chain = xs[0].clone();
+    for x in &xs[1..] {
+      chain += x;
+    }
+    assert_eq!(Value::sum(&xs).data(), chain.data());
+  }
+
+  #[test]
+  fn test_product() {
+    assert_gradients(Value::product, &INPUTS);
+    assert_gradients(|x| Value::product(&x[..3]), &[0.0, 1.5, -2.0]);
+    assert_eq!(Value::<f64>::product(&[]).data(), 1.0);
+
+    let xs = [0.0, 3.0, 0.0, 2.0]
+      .iter()
+      .map(|&x| Value::new(x, None))
+      .collect::<Vec<_>>();
+    Value::product(&xs).backward().unwrap();
+    assert!(xs.iter().all(|x| x.grad() == 0.0));
+
+    let xs = [0.0, 3.0, 2.0]
+      .iter()
+      .map(|&x| Value::new(x, None))
+      .collect::<Vec<_>>();
+    Value::product(&xs).backward().unwrap();
+    assert_eq!(xs[0].grad(), 6.0);
+  }
+
+  #[test]
+  fn test_mean() {
+    let xs = INPUTS
+      .iter()
+      .map(|&x| Value::new(x, None))
+      .collect::<Vec<_>>();
+    let mut y = Value::mean(&xs);
+    y.backward().unwrap();
+    assert_eq!(y.data(), 3.75 / 4.0);
+    assert!(xs.iter().all(|x| x.grad() == 0.25));
+
+    assert_gradients(Value::mean, &INPUTS);
+    assert!(Value::<f64>::mean(&[]).data().is_nan());
+  }
+
+  #[test]
+  fn test_dot() {
+    let a = [Value::new(1.0, None), Value::new(2.0, None)];
+    let b = [Value::new(3.0, None), Value::new(-4.0, None)];
+    let mut y = Value::dot(&a, &b);
+    y.backward().unwrap();
+    assert_eq!(y.data(), -5.0);
+    assert_eq!((a[0].grad(), a[1].grad()), (3.0, -4.0));
+    assert_eq!((b[0].grad(), b[1].grad()), (1.0, 2.0));
+
+    assert_gradients(|x| Value::dot(&x[..2], &x[2..]), &INPUTS);
+    assert_gradients(|x| Value::dot(&x[..2], &x[..2]), &INPUTS);
+  }
+
+  #[test]
+  fn test_sum_product_traits() {
+    let xs = INPUTS
+      .iter()
+      .map(|&x| Value::new(x, None))
+      .collect::<Vec<_>>();
+
+    let total: Value = xs.iter().sum();
+    assert_eq!(total.data(), 3.75);
+    assert_eq!(total.op(), Some("sum".to_string()));
+
+    let total: Value = xs.iter().map(|x| x * x).sum();
+    assert_eq!(total.data(), 2.25 + 0.25 + 4.0 + 0.5625);
+
+    let prod: Value = xs.iter().product();
+    assert_eq!(prod.data(), 1.5 * -0.5 * 2.0 * 0.75);
+
+    let prod: Value = xs.into_iter().product();
+    assert_eq!(prod.op(), Some("product".to_string()));
+  }
+}
@@ -0,0 +1,46 @@
+use std::fmt::{Debug, Display};
+
+use num_traits::Float;
+
+/// The number type a [`crate::Value`] holds. Implemented for every [`Float`]
+/// that can be printed, so `f32`, `f64` and user-defined float types all work.
+pub trait Scalar: Float + Debug + Display + Send + Sync + 'static {
+  /// Converts an `f64` constant, such as the coefficients the ops are
+  /// written with, into this type.
+  fn lit(x: f64) -> Self {
+    <Self as num_traits::NumCast>::from(x)
+      .unwrap_or_else(|| panic!("{} is not representable", x))
+  }
+}
+
+impl<T: Float + Debug + Display + Send + Sync + 'static> Scalar for T {}
+
+/// Like `f64::clamp`, which [`Float`] doesn't provide. NaN stays NaN.
+pub(crate) fn clamp<T: Scalar>(x: T, lo: T, hi: T) -> T {
+  if x < lo {
+    lo
+  } else if x > hi {
+    hi
+  } else {
+    x
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_lit() {
+    assert_eq!(f32::lit(0.5), 0.5f32);
+    assert_eq!(f64::lit(1e300), 1e300);
+    assert_eq!(f32::lit(1e300), f32::INFINITY);
+  }
+
+  #[test]
+  fn test_clamp() {
+    assert_eq!(clamp(2.5f32, -1.0, 1.0), 1.0);
+    assert_eq!(clamp(-0.5, -1.0, 1.0), -0.5);
+    assert!(clamp(f64::NAN, -1.0, 1.0).is_nan());
+  }
+}
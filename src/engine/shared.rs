@@ -0,0 +1,33 @@
+//! The pointer and lock types behind [`crate::Value`]. A node is an
+//! `Arc<Mutex<_>>`, so values and the graphs built from them can be sent
+//! across threads.
+
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+pub(crate) use std::sync::{Arc as Shared, Weak};
+
+/// A mutex with the `borrow`/`borrow_mut` interface of `RefCell`. A panic
+/// while the lock is held doesn't poison it, in line with `RefCell`.
+pub(crate) struct Lock<T>(Mutex<T>);
+
+impl<T> Lock<T> {
+  pub(crate) fn new(value: T) -> Self {
+    Self(Mutex::new(value))
+  }
+
+  pub(crate) fn borrow(&self) -> MutexGuard<'_, T> {
+    self.0.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  pub(crate) fn borrow_mut(&self) -> MutexGuard<'_, T> {
+    self.borrow()
+  }
+
+  pub(crate) fn get_mut(&mut self) -> &mut T {
+    self.0.get_mut().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  pub(crate) fn into_inner(self) -> T {
+    self.0.into_inner().unwrap_or_else(PoisonError::into_inner)
+  }
+}
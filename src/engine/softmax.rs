@@ -0,0 +1,254 @@
+use crate::{Scalar, Value};
+
+use super::op_kind::OpInfo;
+
+impl<T: Scalar> Value<T> {
+  /// `ln(sum(exp(x)))` as a single graph node, computed as
+  /// `m + ln(sum(exp(x - m)))` with `m = max(x)` so large logits don't
+  /// overflow. The gradient is the softmax of the inputs. The empty slice
+  /// gives `-inf`.
+  pub fn logsumexp(values: &[Value<T>]) -> Value<T> {
+    let prev = values.iter().collect::<Vec<_>>();
+    Value::from_op(
+      |inputs| logsumexp(inputs),
+      "logsumexp",
+      &prev,
+      |inputs, _, grad| softmax(inputs).into_iter().map(|s| grad * s).collect(),
+      |prev, out, grad| prev.iter().map(|x| grad * (x - out).exp()).collect(),
+    )
+  }
+
+  /// Softmax of `values`, with the maximum subtracted before exponentiating.
+  /// The maximum and the sum of the exponentials are computed once, as
+  /// nodes shared by the outputs, and each output depends on those and its
+  /// own input only, so evaluating and backpropagating take `O(n)`.
+  pub fn softmax(values: &[Value<T>]) -> Vec<Value<T>> {
+    let Some((max, total)) = normaliser(values) else {
+      return vec![];
+    };
+    (0..values.len())
+      .map(|j| {
+        Value::from_op(
+          |inputs| (inputs[0] - inputs[1]).exp() / inputs[2],
+          OpInfo::with_params(format!("softmax[{}]", j), "softmax", vec![]),
+          &[&values[j], &max, &total],
+          |inputs, value, grad| {
+            let g = grad * value;
+            vec![g, -g, -g / inputs[2]]
+          },
+          |prev, out, grad| {
+            let g = grad * out;
+            vec![g.clone(), -&g, -g / &prev[2]]
+          },
+        )
+      })
+      .collect()
+  }
+
+  /// Log of the softmax of `values`, computed as `(x - m) - ln(sum(exp(x -
+  /// m)))` so it stays finite where the softmax itself underflows to zero.
+  /// Like [`Value::softmax`], it shares `m` and the sum between the outputs.
+  pub fn log_softmax(values: &[Value<T>]) -> Vec<Value<T>> {
+    let Some((max, total)) = normaliser(values) else {
+      return vec![];
+    };
+    let log_total = total.ln();
+    (0..values.len())
+      .map(|j| {
+        Value::from_op(
+          |inputs| (inputs[0] - inputs[1]) - inputs[2],
+          OpInfo::with_params(
+            format!("log_softmax[{}]", j),
+            "log_softmax",
+            vec![],
+          ),
+          &[&values[j], &max, &log_total],
+          |_, _, grad| vec![grad, -grad, -grad],
+          |_, _, grad| vec![grad.clone(), -grad, -grad],
+        )
+      })
+      .collect()
+  }
+}
+
+/// The maximum `m` of `values` and `sum(exp(x - m))`, or `None` for the
+/// empty slice. Neither output depends on which input is the largest:
+/// subtracting `m` doesn't change the softmax, so `m` passes no gradient
+/// on and is only there to keep the exponentials from overflowing.
+fn normaliser<T: Scalar>(values: &[Value<T>]) -> Option<(Value<T>, Value<T>)> {
+  if values.is_empty() {
+    return None;
+  }
+  let prev = values.iter().collect::<Vec<_>>();
+  let max = Value::from_op(
+    |inputs| inputs.iter().copied().fold(T::neg_infinity(), T::max),
+    "softmax_max",
+    &prev,
+    |inputs, _, _| vec![T::zero(); inputs.len()],
+    |prev, _, _| prev.iter().map(|_| Value::constant(T::zero())).collect(),
+  );
+
+  let mut prev = prev;
+  prev.push(&max);
+  let total = Value::from_op(
+    |inputs| {
+      let (&m, xs) = inputs.split_last().unwrap();
+      xs.iter().fold(T::zero(), |acc, &x| acc + (x - m).exp())
+    },
+    "softmax_sum",
+    &prev,
+    |inputs, value, grad| {
+      let (&m, xs) = inputs.split_last().unwrap();
+      let mut grads =
+        xs.iter().map(|&x| grad * (x - m).exp()).collect::<Vec<_>>();
+      grads.push(-grad * value);
+      grads
+    },
+    |prev, out, grad| {
+      let (m, xs) = prev.split_last().unwrap();
+      let mut grads =
+        xs.iter().map(|x| grad * (x - m).exp()).collect::<Vec<_>>();
+      grads.push(-(grad * out));
+      grads
+    },
+  );
+  Some((max, total))
+}
+
+fn logsumexp<T: Scalar>(values: &[T]) -> T {
+  let max = values.iter().copied().fold(T::neg_infinity(), T::max);
+  if !max.is_finite() {
+    return max;
+  }
+  let total = values
+    .iter()
+    .fold(T::zero(), |acc, &x| acc + (x - max).exp());
+  max + total.ln()
+}
+
+fn softmax<T: Scalar>(values: &[T]) -> Vec<T> {
+  let max = values.iter().copied().fold(T::neg_infinity(), T::max);
+  let exps = values.iter().map(|&x| (x - max).exp()).collect::<Vec<_>>();
+  let total = exps.iter().fold(T::zero(), |acc, &e| acc + e);
+  exps.into_iter().map(|e| e / total).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::Value;
+  use crate::engine::autograd::leaves;
+  use crate::engine::gradcheck::assert_gradients;
+
+  const LOGITS: [f64; 3] = [0.5, -1.2, 2.0];
+
+  #[test]
+  fn test_logsumexp() {
+    let y = Value::logsumexp(&leaves(&LOGITS));
+    let expected = LOGITS.iter().map(|x| x.exp()).sum::<f64>().ln();
+    assert!((y.data() - expected).abs() < 1e-12);
+    assert_eq!(y.op(), Some("logsumexp".to_string()));
+
+    assert_gradients(Value::logsumexp, &LOGITS);
+    assert_eq!(Value::<f64>::logsumexp(&[]).data(), f64::NEG_INFINITY);
+  }
+
+  #[test]
+  fn test_softmax() {
+    let xs = leaves(&LOGITS);
+    let s = Value::softmax(&xs);
+    let total = s.iter().map(Value::data).sum::<f64>();
+    assert!((total - 1.0).abs() < 1e-12);
+    assert!(s[2].data() > s[0].data() && s[0].data() > s[1].data());
+
+    for j in 0..LOGITS.len() {
+      assert_gradients(|x| Value::softmax(x).remove(j), &LOGITS);
+    }
+
+    // The outputs always sum to one, so their gradients cancel.
+    let mut total: Value = Value::sum(&s);
+    total.backward().unwrap();
+    assert!(xs.iter().all(|x| x.grad().abs() < 1e-12));
+  }
+
+  #[test]
+  fn test_shares_normaliser() {
+    let data = (0..1000)
+      .map(|i| (i as f64 * 0.37).sin())
+      .collect::<Vec<_>>();
+    let xs = leaves(&data);
+    let log_s = Value::log_softmax(&xs);
+    let s = Value::softmax(&xs);
+    for (j, (a, b)) in log_s.iter().zip(&s).enumerate() {
+      assert_eq!(a.prev()[0], xs[j]);
+      assert_eq!(a.prev()[1..], log_s[0].prev()[1..]);
+      assert_eq!(b.prev()[0], xs[j]);
+      assert_eq!(b.prev()[1..], s[0].prev()[1..]);
+    }
+
+    // d/dx_i sum_j log_softmax_j = 1 - n * softmax_i
+    let mut total: Value = Value::sum(&log_s);
+    total.backward().unwrap();
+    for (x, s) in xs.iter().zip(&s) {
+      assert!((x.grad() - (1.0 - 1000.0 * s.data())).abs() < 1e-9);
+    }
+  }
+
+  #[test]
+  fn test_log_softmax() {
+    let xs = leaves(&LOGITS);
+    let log_s = Value::log_softmax(&xs);
+    let s = Value::softmax(&xs);
+    for (a, b) in log_s.iter().zip(&s) {
+      assert!((a.data() - b.data().ln()).abs() < 1e-12);
+    }
+
+    for j in 0..LOGITS.len() {
+      assert_gradients(|x| Value::log_softmax(x).remove(j), &LOGITS);
+    }
+  }
+
+  #[test]
+  fn test_cross_entropy() {
+    let xs = leaves(&LOGITS);
+    let target = 1;
+    let mut loss = -Value::log_softmax(&xs).remove(target);
+    loss.backward().unwrap();
+
+    let s = Value::softmax(&leaves(&LOGITS));
+    for (i, x) in xs.iter().enumerate() {
+      let expected = s[i].data() - if i == target { 1.0 } else { 0.0 };
+      assert!((x.grad() - expected).abs() < 1e-12);
+    }
+  }
+
+  #[test]
+  fn test_large_logits() {
+    let xs = leaves(&[1000.0, 1000.0, -1000.0]);
+
+    let lse = Value::logsumexp(&xs);
+    assert_eq!(lse.data(), 1000.0 + 2f64.ln());
+
+    let s = Value::softmax(&xs);
+    assert_eq!(s[0].data(), 0.5);
+    assert_eq!(s[1].data(), 0.5);
+    assert_eq!(s[2].data(), 0.0);
+
+    let log_s = Value::log_softmax(&xs);
+    assert_eq!(log_s[0].data(), -(2f64.ln()));
+    assert!((log_s[2].data() - (-2000.0 - 2f64.ln())).abs() < 1e-9);
+
+    let mut loss = -log_s[2].clone();
+    loss.backward().unwrap();
+    assert_eq!(xs[0].grad(), 0.5);
+    assert_eq!(xs[1].grad(), 0.5);
+    assert_eq!(xs[2].grad(), -1.0);
+
+    let xs = leaves(&[-1000.0f64, -999.0]);
+    let s = Value::softmax(&xs);
+    assert!(s.iter().all(|s| s.data().is_finite()));
+    let e = 1f64.exp();
+    assert!((s[1].data() - e / (1.0 + e)).abs() < 1e-12);
+    assert_gradients(|x| Value::log_softmax(x).remove(0), &[-1000.0, -999.0]);
+    assert_gradients(|x| Value::softmax(x).remove(1), &[1000.0, 999.5]);
+  }
+}
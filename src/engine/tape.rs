@@ -0,0 +1,628 @@
+//! The tape behind [`Tape`]: a contiguous list of recorded ops (a Wengert
+//! list) with index-based nodes, swept backwards in a single pass that
+//! dispatches on an enum for the common ops.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::{GradFn, Scalar, Value};
+
+use super::Repr;
+use super::autograd::grad_from;
+use super::math::powf_local_grad;
+use super::op_kind::{OpInfo, OpKind};
+use super::shared::{Lock, Shared};
+
+thread_local! {
+  /// The recording of the innermost [`Tape::record`] on this thread.
+  static RECORDING: RefCell<Option<Shared<dyn Any + Send + Sync>>> =
+    const { RefCell::new(None) };
+}
+
+/// The op that produced a node, with the positions of its inputs.
+#[derive(Debug, Clone, Copy)]
+enum Op<T> {
+  Leaf,
+  Add(usize, usize),
+  Sub(usize, usize),
+  Mul(usize, usize),
+  Div(usize, usize),
+  Neg(usize),
+  Powf(usize, T),
+  Exp(usize),
+  Ln(usize),
+  Tanh(usize),
+  Relu(usize),
+  Sigmoid(usize),
+  /// Any other op, backpropagated through the gradient function `grad_fn`
+  /// of the recording. Its inputs are `args[start..end]`.
+  Dyn {
+    grad_fn: usize,
+    start: usize,
+    end: usize,
+  },
+}
+
+impl<T: Scalar> Op<T> {
+  /// The enum op for a built-in op of `kind` on `inputs`, if it has one.
+  fn from_kind(kind: &OpKind, inputs: &[usize]) -> Option<Op<T>> {
+    let op = match (kind.name(), inputs) {
+      ("+", &[a, b]) => Op::Add(a, b),
+      ("-", &[a, b]) => Op::Sub(a, b),
+      ("*", &[a, b]) => Op::Mul(a, b),
+      ("/", &[a, b]) => Op::Div(a, b),
+      ("neg", &[a]) => Op::Neg(a),
+      ("powf", &[a]) => Op::Powf(a, T::lit(f64::from_bits(kind.params()[0]))),
+      ("exp", &[a]) => Op::Exp(a),
+      ("ln", &[a]) => Op::Ln(a),
+      ("tanh", &[a]) => Op::Tanh(a),
+      ("relu", &[a]) => Op::Relu(a),
+      ("sigmoid", &[a]) => Op::Sigmoid(a),
+      _ => return None,
+    };
+    Some(op)
+  }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Node<T> {
+  data: T,
+  op: Op<T>,
+}
+
+/// The nodes recorded by one [`Tape::record`]. Every node is appended after
+/// its inputs, so the nodes are always in topological order.
+pub(crate) struct Recording<T: Scalar> {
+  nodes: Vec<Node<T>>,
+  args: Vec<usize>,
+  grad_fns: Vec<GradFn<T>>,
+  /// The graph values used in the recording and the leaves standing for
+  /// them. Holding the values keeps their addresses, the keys of `linked`,
+  /// from being reused.
+  links: Vec<(usize, Value<T>)>,
+  linked: HashMap<usize, usize>,
+  /// The gradients of the last backward pass, one per node up to the last
+  /// root.
+  grads: Vec<T>,
+}
+
+impl<T: Scalar> Recording<T> {
+  fn with_capacity(capacity: usize) -> Self {
+    Self {
+      nodes: Vec::with_capacity(capacity),
+      args: vec![],
+      grad_fns: vec![],
+      links: vec![],
+      linked: HashMap::new(),
+      grads: vec![],
+    }
+  }
+
+  fn clear(&mut self) {
+    self.nodes.clear();
+    self.args.clear();
+    self.grad_fns.clear();
+    self.links.clear();
+    self.linked.clear();
+    self.grads.clear();
+  }
+
+  pub(crate) fn data(&self, index: usize) -> T {
+    self.nodes[index].data
+  }
+
+  pub(crate) fn grad(&self, index: usize) -> T {
+    self.grads.get(index).copied().unwrap_or(T::zero())
+  }
+
+  /// The position of `value` on the tape `this`, which is this recording.
+  /// A graph value is pushed as a leaf the first time it is used.
+  ///
+  /// # Panics
+  ///
+  /// Panics if `value` was recorded on another tape.
+  fn index_of(&mut self, this: &Shared<Lock<Self>>, value: &Value<T>) -> usize {
+    let node = match &value.repr {
+      Repr::Tape(recording, index) => {
+        assert!(
+          Shared::ptr_eq(recording, this),
+          "operands belong to different tapes"
+        );
+        return *index;
+      }
+      Repr::Node(node) => node,
+    };
+    let key = Shared::as_ptr(node) as usize;
+    if let Some(&index) = self.linked.get(&key) {
+      return index;
+    }
+    let index = self.nodes.len();
+    self.nodes.push(Node {
+      data: value.data(),
+      op: Op::Leaf,
+    });
+    self.links.push((index, value.clone()));
+    self.linked.insert(key, index);
+    index
+  }
+
+  /// Backpropagates `seeds`, given as positions and gradients, into `grads`,
+  /// which ends up with one entry per node up to the last seeded one.
+  fn sweep(&self, seeds: &[(usize, T)], grads: &mut Vec<T>) {
+    let len = seeds.iter().map(|&(i, _)| i + 1).max().unwrap_or(0);
+    grads.clear();
+    grads.resize(len, T::zero());
+    for &(i, seed) in seeds {
+      grads[i] = grads[i] + seed;
+    }
+
+    let nodes = &self.nodes;
+    let mut inputs = vec![];
+    for i in (0..len).rev() {
+      let Node { data: out, op } = nodes[i];
+      let grad = grads[i];
+      let mut acc = |j: usize, g: T| grads[j] = grads[j] + g;
+      match op {
+        Op::Leaf => {}
+        Op::Add(a, b) => {
+          acc(a, grad);
+          acc(b, grad);
+        }
+        Op::Sub(a, b) => {
+          acc(a, grad);
+          acc(b, -grad);
+        }
+        Op::Mul(a, b) => {
+          acc(a, grad * nodes[b].data);
+          acc(b, grad * nodes[a].data);
+        }
+        Op::Div(a, b) => {
+          let (x, y) = (nodes[a].data, nodes[b].data);
+          acc(a, grad / y);
+          acc(b, -grad * x / (y * y));
+        }
+        Op::Neg(a) => acc(a, -grad),
+        Op::Powf(a, k) => acc(a, grad * powf_local_grad(nodes[a].data, k)),
+        Op::Exp(a) => acc(a, grad * out),
+        Op::Ln(a) => acc(a, grad / nodes[a].data),
+        Op::Tanh(a) => acc(a, grad * (T::one() - out * out)),
+        Op::Relu(a) => {
+          let local_grad = if nodes[a].data > T::zero() {
+            T::one()
+          } else {
+            T::zero()
+          };
+          acc(a, grad * local_grad);
+        }
+        Op::Sigmoid(a) => acc(a, grad * out * (T::one() - out)),
+        Op::Dyn {
+          grad_fn,
+          start,
+          end,
+        } => {
+          let args = &self.args[start..end];
+          inputs.clear();
+          inputs.extend(args.iter().map(|&j| nodes[j].data));
+          let input_grads = self.grad_fns[grad_fn](&inputs, out, grad);
+          for (&j, g) in args.iter().zip(input_grads) {
+            acc(j, g);
+          }
+        }
+      }
+    }
+  }
+
+  /// The gradients `grads` of the leaves linked to graph values that
+  /// require one, paired with those values.
+  fn linked_grads(&self, grads: &[T]) -> Vec<(Value<T>, T)> {
+    self
+      .links
+      .iter()
+      .filter(|(i, value)| *i < grads.len() && value.requires_grad())
+      .map(|(i, value)| (value.clone(), grads[*i]))
+      .collect()
+  }
+}
+
+/// Records ops onto a contiguous tape instead of allocating a
+/// reference-counted node per op. Inside [`Tape::record`], every op on
+/// [`Value`]s that would create a graph node appends to the tape and returns
+/// a value that is a position on it, so code written against `Value`, such
+/// as [`crate::Module::call`], runs on the tape unchanged. Outside `record`,
+/// an op with an input on a tape records onto that tape.
+///
+/// [`Value::backward`] on a recorded value is a single reverse sweep over
+/// the tape, dispatching on an enum for the common ops and calling the
+/// gradient function of the others. A graph value used in a recording, like
+/// a parameter of a model, is a leaf of the tape linked to the value: its
+/// gradient is handed to the graph, which accumulates it, runs the hooks and
+/// backpropagates it further as if the tape were a single op.
+///
+/// A recorded value has no id, label or inputs of its own, so the methods
+/// that inspect or rewrite graph nodes, like [`Value::register_hook`],
+/// [`crate::grad_graph`] or rendering, panic for it, and anomaly detection
+/// doesn't look at the tape. `backward` doesn't release the tape: it is
+/// freed with its last value or reused by the next `record`, which keeps the
+/// allocation and makes rebuilding the graph on every training step cheap.
+pub struct Tape<T: Scalar = f64> {
+  recording: Shared<Lock<Recording<T>>>,
+}
+
+impl<T: Scalar> Tape<T> {
+  pub fn new() -> Self {
+    Self::with_capacity(0)
+  }
+
+  pub fn with_capacity(capacity: usize) -> Self {
+    Self {
+      recording: Shared::new(Lock::new(Recording::with_capacity(capacity))),
+    }
+  }
+
+  /// Runs `f` with the ops on the current thread recorded onto this tape,
+  /// replacing what the last call recorded. The values of the last call
+  /// stay valid: if any is still around, the tape starts a new recording
+  /// rather than reusing the old one.
+  ///
+  /// Grad mode still applies, so ops inside [`crate::no_grad`] return
+  /// constants, and ops on values of another scalar type build graph nodes.
+  pub fn record<R>(&mut self, f: impl FnOnce() -> R) -> R {
+    match Shared::get_mut(&mut self.recording) {
+      Some(recording) => recording.get_mut().clear(),
+      None => {
+        let capacity = self.recording.borrow().nodes.capacity();
+        *self = Self::with_capacity(capacity);
+      }
+    }
+    let _guard = RecordingGuard::new(self.recording.clone());
+    f()
+  }
+
+  /// The number of nodes of the last recording, including a leaf for each
+  /// graph value it used.
+  pub fn len(&self) -> usize {
+    self.recording.borrow().nodes.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.recording.borrow().nodes.is_empty()
+  }
+}
+
+impl<T: Scalar> Default for Tape<T> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Restores the previous recording when dropped, even if `f` panics.
+struct RecordingGuard {
+  prev: Option<Shared<dyn Any + Send + Sync>>,
+}
+
+impl RecordingGuard {
+  fn new(recording: Shared<dyn Any + Send + Sync>) -> Self {
+    let prev = RECORDING.with(|cell| cell.replace(Some(recording)));
+    Self { prev }
+  }
+}
+
+impl Drop for RecordingGuard {
+  fn drop(&mut self) {
+    RECORDING.with(|cell| *cell.borrow_mut() = self.prev.take());
+  }
+}
+
+/// The tape an op on `prev` records onto: the one recording on this thread,
+/// if it holds `T`, or else the tape of the first recorded input.
+pub(crate) fn recording_for<T: Scalar>(
+  prev: &[&Value<T>],
+) -> Option<Shared<Lock<Recording<T>>>> {
+  let current = RECORDING.with(|cell| cell.borrow().clone());
+  if let Some(Ok(recording)) = current.map(|current| current.downcast()) {
+    return Some(recording);
+  }
+  prev.iter().find_map(|v| match &v.repr {
+    Repr::Tape(recording, _) => Some(recording.clone()),
+    Repr::Node(_) => None,
+  })
+}
+
+/// Appends the op `op` on `prev`, which evaluated to `data`, to `recording`.
+/// Ops without an enum form keep `grad_fn` for the backward pass.
+pub(crate) fn push<T: Scalar>(
+  recording: Shared<Lock<Recording<T>>>,
+  data: T,
+  op: &OpInfo,
+  prev: &[&Value<T>],
+  grad_fn: impl Fn(&[T], T, T) -> Vec<T> + Send + Sync + 'static,
+) -> Value<T> {
+  let index = {
+    let mut rec = recording.borrow_mut();
+    let start = rec.args.len();
+    for v in prev {
+      let index = rec.index_of(&recording, v);
+      rec.args.push(index);
+    }
+
+    let kind = op.kind.as_ref();
+    let op = match kind.and_then(|kind| Op::from_kind(kind, &rec.args[start..]))
+    {
+      Some(op) => {
+        rec.args.truncate(start);
+        op
+      }
+      None => {
+        rec.grad_fns.push(Box::new(grad_fn));
+        Op::Dyn {
+          grad_fn: rec.grad_fns.len() - 1,
+          start,
+          end: rec.args.len(),
+        }
+      }
+    };
+    rec.nodes.push(Node { data, op });
+    rec.nodes.len() - 1
+  };
+  Value {
+    repr: Repr::Tape(recording, index),
+  }
+}
+
+/// Backpropagates from the recorded `roots`, keeping the gradients on each
+/// tape for [`Value::grad`], and returns the gradients of the graph values
+/// the tapes used, which the caller has to pass on into the graph.
+pub(crate) fn backward<T: Scalar>(
+  roots: &[(Value<T>, T)],
+) -> Vec<(Value<T>, T)> {
+  let mut linked = vec![];
+  let mut done: Vec<&Shared<Lock<Recording<T>>>> = vec![];
+  for (root, _) in roots {
+    let Repr::Tape(recording, _) = &root.repr else {
+      continue;
+    };
+    if done.iter().any(|r| Shared::ptr_eq(r, recording)) {
+      continue;
+    }
+    done.push(recording);
+
+    let seeds = roots
+      .iter()
+      .filter_map(|(v, seed)| match &v.repr {
+        Repr::Tape(r, i) if Shared::ptr_eq(r, recording) => Some((*i, *seed)),
+        _ => None,
+      })
+      .collect::<Vec<_>>();
+    let mut rec = recording.borrow_mut();
+    let mut grads = std::mem::take(&mut rec.grads);
+    rec.sweep(&seeds, &mut grads);
+    linked.extend(rec.linked_grads(&grads));
+    rec.grads = grads;
+  }
+  linked
+}
+
+/// [`crate::grad`] for a recorded `output`. The gradients with respect to
+/// graph values continue from the leaves linked to them.
+///
+/// # Panics
+///
+/// Panics if one of `wrt` was recorded on another tape than `output`.
+pub(crate) fn grad<T: Scalar>(
+  output: &Value<T>,
+  wrt: &[Value<T>],
+  leaf_hooks: bool,
+) -> Vec<T> {
+  let Repr::Tape(recording, index) = &output.repr else {
+    panic!("output was not recorded on a tape");
+  };
+  let (grads, linked) = {
+    let rec = recording.borrow();
+    let mut grads = vec![];
+    rec.sweep(&[(*index, T::one())], &mut grads);
+    let linked = rec.linked_grads(&grads);
+    (grads, linked)
+  };
+
+  let graph_wrt = wrt
+    .iter()
+    .filter(|v| !v.is_recorded())
+    .cloned()
+    .collect::<Vec<_>>();
+  let mut graph_grads = grad_from(&linked, &graph_wrt, leaf_hooks).into_iter();
+
+  wrt
+    .iter()
+    .map(|v| match &v.repr {
+      Repr::Tape(r, i) => {
+        assert!(
+          Shared::ptr_eq(r, recording),
+          "value was recorded on another tape than the output"
+        );
+        grads.get(*i).copied().unwrap_or(T::zero())
+      }
+      Repr::Node(_) => graph_grads.next().unwrap(),
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{MLP, Module, grad, no_grad};
+  use rand::{SeedableRng, rngs::StdRng};
+
+  #[test]
+  fn test_example_1() {
+    let x = Value::new(-4.0, None);
+    let mut tape: Tape = Tape::new();
+    let mut y = tape.record(|| {
+      let z: Value = 2.0 * &x + 2.0 + &x;
+      let q = z.relu() + &z * &x;
+      let h = (&z * &z).relu();
+      &h + &q + &q * &x
+    });
+
+    y.backward().unwrap();
+
+    assert!(y.is_recorded());
+    assert_eq!(-20.0, y.data());
+    assert_eq!(1.0, y.grad());
+    assert_eq!(46.0, x.grad());
+  }
+
+  #[test]
+  fn test_matches_graph() {
+    let f = |a: &Value, b: &Value| {
+      ((a * b).tanh() - b / a + (-a).exp() * b.sigmoid()).powf(2.0)
+        + (a * a).ln()
+        + b.relu()
+        + a.sin() * b.softplus()
+    };
+
+    let (a, b) = (Value::new(0.7, None), Value::new(-1.3, None));
+    let mut expected = f(&a, &b);
+    expected.backward().unwrap();
+    let expected_grads = [a.grad(), b.grad()];
+
+    let (a, b) = (Value::new(0.7, None), Value::new(-1.3, None));
+    let mut tape: Tape = Tape::new();
+    let mut y = tape.record(|| f(&a, &b));
+    y.backward().unwrap();
+
+    assert_eq!(y.data(), expected.data());
+    for (x, expected) in [a, b].iter().zip(expected_grads) {
+      assert!((x.grad() - expected).abs() < 1e-12);
+    }
+  }
+
+  #[test]
+  fn test_grad() {
+    let (a, b) = (Value::new(1.5, None), Value::new(-0.5, None));
+    let mut tape: Tape = Tape::new();
+    let (t, y) = tape.record(|| {
+      let t = &a * &b;
+      let y = t.tanh() * &a;
+      (t, y)
+    });
+
+    let expected: [f64; 2] = [a.data(), b.data()];
+    let th = (expected[0] * expected[1]).tanh();
+    let dt = (1.0 - th * th) * expected[0];
+    let grads = grad(&y, &[a.clone(), t.clone(), b.clone()]);
+    assert_eq!(grads, [th + dt * expected[1], dt, dt * expected[0]]);
+    assert_eq!(a.grad(), 0.0);
+  }
+
+  #[test]
+  #[should_panic(expected = "another tape")]
+  fn test_grad_checks_tape() {
+    let x = Value::new(2.0, None);
+    let (mut t1, mut t2): (Tape, Tape) = (Tape::new(), Tape::new());
+    let y = t1.record(|| &x * &x);
+    let z = t2.record(|| &x * &x);
+    grad(&y, &[z]);
+  }
+
+  #[test]
+  #[should_panic(expected = "different tapes")]
+  fn test_mixing_tapes_panics() {
+    let x = Value::new(2.0, None);
+    let (mut t1, mut t2): (Tape, Tape) = (Tape::new(), Tape::new());
+    let y = t1.record(|| &x * &x);
+    t2.record(|| &y * &x);
+  }
+
+  #[test]
+  fn test_links_graph_values() {
+    let x = Value::new(3.0, None);
+    let calls = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+    let seen = calls.clone();
+    x.register_hook(move |g: f64| {
+      seen.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+      g
+    });
+    let squared: Value = &x * &x;
+
+    let mut tape: Tape = Tape::new();
+    let mut y = tape.record(|| &squared * &x + &x);
+    // x, the square and the two ops.
+    assert_eq!(tape.len(), 4);
+
+    y.backward().unwrap();
+    assert_eq!(x.grad(), 3.0 * 9.0 + 1.0);
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+    assert!(squared.is_released());
+  }
+
+  #[test]
+  fn test_record_reuses_tape() {
+    let x = Value::new(2.0f32, None);
+    let mut tape = Tape::<f32>::with_capacity(16);
+    for _ in 0..3 {
+      let mut y = tape.record(|| &x * &x * &x);
+      assert_eq!(tape.len(), 3);
+      y.backward().unwrap();
+    }
+    assert_eq!(x.grad(), 3.0 * 12.0);
+
+    // A value that outlives its recording keeps it.
+    let y = tape.record(|| &x * &x);
+    let mut z: Value<f32> = tape.record(|| &x + 1);
+    assert_eq!(y.data(), 4.0);
+    assert_eq!(z.data(), 3.0);
+    let mut w: Value<f32> = &y * 2;
+    w.backward().unwrap();
+    z.backward().unwrap();
+    assert_eq!(x.grad(), 36.0 + 8.0 + 1.0);
+  }
+
+  #[test]
+  fn test_no_grad_inside_record() {
+    let x = Value::new(2.0, None);
+    let mut tape: Tape = Tape::new();
+    let y = tape.record(|| no_grad(|| &x * &x));
+    assert!(!y.is_recorded());
+    assert!(!y.requires_grad());
+    assert!(tape.is_empty());
+  }
+
+  #[test]
+  fn test_mlp_training() {
+    let mut mlp = MLP::new(3, &[4, 4, 1], &mut StdRng::from_seed([0u8; 32]));
+    let xs = [
+      [2.0, 3.0, -1.0],
+      [3.0, -1.0, 0.5],
+      [0.5, 1.0, 1.0],
+      [1.0, 1.0, -1.0],
+    ]
+    .map(|x| x.map(Value::constant));
+    let ys = [1.0, -1.0, -1.0, 1.0];
+
+    let mut tape: Tape = Tape::new();
+    let mut last_loss = f64::MAX;
+    for _ in 0..10_000 {
+      let mut loss = tape.record(|| {
+        let errors = xs.iter().zip(ys).map(|(x, y)| {
+          let e = mlp.call(&x.iter().collect::<Vec<_>>()).remove(0) - y;
+          &e * &e
+        });
+        Value::sum(&errors.collect::<Vec<_>>())
+      });
+
+      mlp.zero_grad();
+      loss.backward().unwrap();
+      for mut p in mlp.parameters() {
+        p.set_data(p.data() - 0.1 * p.grad());
+      }
+
+      last_loss = last_loss.min(loss.data());
+      if loss.data() < 0.001 {
+        break;
+      }
+    }
+
+    assert!(last_loss < 0.001);
+  }
+}
@@ -0,0 +1,238 @@
+use crate::{Scalar, Value};
+
+impl<T: Scalar> Value<T> {
+  pub fn sin(&self) -> Value<T> {
+    Value::from_op(
+      |inputs| inputs[0].sin(),
+      "sin",
+      &[self],
+      |inputs, _, grad| vec![grad * inputs[0].cos()],
+      |prev, _, grad| vec![grad * prev[0].cos()],
+    )
+  }
+
+  pub fn cos(&self) -> Value<T> {
+    Value::from_op(
+      |inputs| inputs[0].cos(),
+      "cos",
+      &[self],
+      |inputs, _, grad| vec![-grad * inputs[0].sin()],
+      |prev, _, grad| vec![-grad * prev[0].sin()],
+    )
+  }
+
+  pub fn tan(&self) -> Value<T> {
+    Value::from_op(
+      |inputs| inputs[0].tan(),
+      "tan",
+      &[self],
+      |_, value, grad| vec![grad * (T::one() + value * value)],
+      |_, out, grad| vec![grad * (out * out + 1.0)],
+    )
+  }
+
+  /// Inverse sine. NaN outside `[-1, 1]`, with an infinite gradient at the
+  /// bounds.
+  pub fn asin(&self) -> Value<T> {
+    Value::from_op(
+      |inputs| inputs[0].asin(),
+      "asin",
+      &[self],
+      |inputs, _, grad| {
+        let x = inputs[0];
+        vec![grad / (T::one() - x * x).sqrt()]
+      },
+      |prev, _, grad| {
+        let x = &prev[0];
+        let r: Value<T> = 1.0 - x * x;
+        vec![grad / r.sqrt()]
+      },
+    )
+  }
+
+  /// Inverse cosine. NaN outside `[-1, 1]`, with an infinite gradient at the
+  /// bounds.
+  pub fn acos(&self) -> Value<T> {
+    Value::from_op(
+      |inputs| inputs[0].acos(),
+      "acos",
+      &[self],
+      |inputs, _, grad| {
+        let x = inputs[0];
+        vec![-grad / (T::one() - x * x).sqrt()]
+      },
+      |prev, _, grad| {
+        let x = &prev[0];
+        let r: Value<T> = 1.0 - x * x;
+        vec![-grad / r.sqrt()]
+      },
+    )
+  }
+
+  pub fn atan(&self) -> Value<T> {
+    Value::from_op(
+      |inputs| inputs[0].atan(),
+      "atan",
+      &[self],
+      |inputs, _, grad| {
+        let x = inputs[0];
+        vec![grad / (T::one() + x * x)]
+      },
+      |prev, _, grad| {
+        let x = &prev[0];
+        vec![grad / (x * x + 1.0)]
+      },
+    )
+  }
+
+  /// Four-quadrant arctangent of `self / x`, like `f64::atan2`. Both
+  /// gradients are NaN at the origin, where the angle is undefined.
+  pub fn atan2(&self, x: &Value<T>) -> Value<T> {
+    Value::from_op(
+      |inputs| inputs[0].atan2(inputs[1]),
+      "atan2",
+      &[self, x],
+      |inputs, _, grad| {
+        let (y, x) = (inputs[0], inputs[1]);
+        let r2 = x * x + y * y;
+        vec![grad * x / r2, -grad * y / r2]
+      },
+      |prev, _, grad| {
+        let (y, x) = (&prev[0], &prev[1]);
+        let r2 = x * x + y * y;
+        vec![grad * x / &r2, -grad * y / &r2]
+      },
+    )
+  }
+
+  pub fn sinh(&self) -> Value<T> {
+    Value::from_op(
+      |inputs| inputs[0].sinh(),
+      "sinh",
+      &[self],
+      |inputs, _, grad| vec![grad * inputs[0].cosh()],
+      |prev, _, grad| vec![grad * prev[0].cosh()],
+    )
+  }
+
+  pub fn cosh(&self) -> Value<T> {
+    Value::from_op(
+      |inputs| inputs[0].cosh(),
+      "cosh",
+      &[self],
+      |inputs, _, grad| vec![grad * inputs[0].sinh()],
+      |prev, _, grad| vec![grad * prev[0].sinh()],
+    )
+  }
+
+  pub fn asinh(&self) -> Value<T> {
+    Value::from_op(
+      |inputs| inputs[0].asinh(),
+      "asinh",
+      &[self],
+      |inputs, _, grad| {
+        let x = inputs[0];
+        vec![grad / (x * x + T::one()).sqrt()]
+      },
+      |prev, _, grad| {
+        let x = &prev[0];
+        let r: Value<T> = x * x + 1.0;
+        vec![grad / r.sqrt()]
+      },
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::Value;
+  use crate::engine::gradcheck::assert_gradients;
+
+  const POINTS: [f64; 6] = [-2.8, -1.1, -0.4, 0.3, 0.9, 2.2];
+  const UNIT_POINTS: [f64; 5] = [-0.9, -0.5, 0.0, 0.4, 0.8];
+
+  #[test]
+  fn test_sin_cos_tan() {
+    let x = Value::new(0.5, None);
+    let mut y = x.sin() * x.cos();
+    y.backward().unwrap();
+    assert_eq!(x.grad(), 0.5f64.cos().powi(2) - 0.5f64.sin().powi(2));
+
+    for x in POINTS {
+      assert_gradients(|v| v[0].sin(), &[x]);
+      assert_gradients(|v| v[0].cos(), &[x]);
+    }
+    for x in [-1.2, -0.4, 0.3, 1.1] {
+      assert_gradients(|v| v[0].tan(), &[x]);
+    }
+  }
+
+  #[test]
+  fn test_asin_acos() {
+    for x in UNIT_POINTS {
+      assert_gradients(|v| v[0].asin(), &[x]);
+      assert_gradients(|v| v[0].acos(), &[x]);
+    }
+
+    let edge = Value::new(1.0, None);
+    let mut y = edge.asin();
+    y.backward().unwrap();
+    assert_eq!(y.data(), std::f64::consts::FRAC_PI_2);
+    assert_eq!(edge.grad(), f64::INFINITY);
+
+    assert!(Value::new(1.5f64, None).asin().data().is_nan());
+    assert!(Value::new(-1.5f64, None).acos().data().is_nan());
+  }
+
+  #[test]
+  fn test_atan() {
+    for x in POINTS {
+      assert_gradients(|v| v[0].atan(), &[x]);
+    }
+  }
+
+  #[test]
+  fn test_atan2() {
+    let y = Value::new(1.0, None);
+    let x = Value::new(-1.0, None);
+    let mut angle = y.atan2(&x);
+    angle.backward().unwrap();
+    assert_eq!(angle.data(), 3.0 * std::f64::consts::FRAC_PI_4);
+    assert_eq!(y.grad(), -0.5);
+    assert_eq!(x.grad(), -0.5);
+
+    for (y, x) in [(0.5, 1.5), (1.2, -0.3), (-0.7, -2.0), (-1.1, 0.4)] {
+      assert_gradients(|v| v[0].atan2(&v[1]), &[y, x]);
+    }
+  }
+
+  #[test]
+  fn test_hyperbolic() {
+    for x in POINTS {
+      assert_gradients(|v| v[0].sinh(), &[x]);
+      assert_gradients(|v| v[0].cosh(), &[x]);
+      assert_gradients(|v| v[0].asinh(), &[x]);
+    }
+    assert_eq!(Value::new(0.0, None).cosh().data(), 1.0);
+  }
+
+  #[test]
+  fn test_periodic_fit_gradient() {
+    // d/dw sum_i (sin(w t_i) - y_i)^2
+    let w = Value::new(1.3, Some("w"));
+    let samples: [(f64, f64); 3] = [(0.5, 0.4), (1.0, 0.9), (1.5, 1.0)];
+    let mut loss = Value::constant(0.0);
+    for (t, y) in samples {
+      let wt: Value = &w * t;
+      let err = wt.sin() - y;
+      loss += &err * &err;
+    }
+    loss.backward().unwrap();
+
+    let expected = samples
+      .iter()
+      .map(|&(t, y)| 2.0 * ((1.3 * t).sin() - y) * (1.3 * t).cos() * t)
+      .sum::<f64>();
+    assert!((w.grad() - expected).abs() < 1e-12);
+  }
+}
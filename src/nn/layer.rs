@@ -1,12 +1,13 @@
 use rand::Rng;
+use rand::distr::{Distribution, StandardUniform};
 
-use crate::{Module, Neuron, Value};
+use crate::{Module, Neuron, Scalar, Value};
 
-pub struct Layer {
-  neurons: Vec<Neuron>,
+pub struct Layer<T: Scalar = f64> {
+  neurons: Vec<Neuron<T>>,
 }
 
-impl Layer {
+impl<T: Scalar> Layer<T> {
   pub fn new<R>(
     num_inputs: usize,
     num_outputs: usize,
@@ -15,6 +16,7 @@ impl Layer {
   ) -> Self
   where
     R: Rng,
+    StandardUniform: Distribution<T>,
   {
     let neurons = (0..num_outputs)
       .map(|_| Neuron::new(num_inputs, nonlin, rng))
@@ -24,8 +26,8 @@ impl Layer {
   }
 }
 
-impl Module for Layer {
-  fn parameters(&self) -> Vec<Value> {
+impl<T: Scalar> Module<T> for Layer<T> {
+  fn parameters(&self) -> Vec<Value<T>> {
     let mut result = vec![];
     for neuron in self.neurons.iter() {
       result.extend(neuron.parameters());
@@ -33,7 +35,7 @@ impl Module for Layer {
     result
   }
 
-  fn call(&self, inputs: &[&Value]) -> Vec<Value> {
+  fn call(&self, inputs: &[&Value<T>]) -> Vec<Value<T>> {
     let mut result = vec![];
     for neuron in self.neurons.iter() {
       result.extend(neuron.call(inputs));
@@ -70,7 +72,7 @@ mod tests {
     }
     output.set_label("output");
 
-    output.backward();
+    output.backward_retain_graph().unwrap();
 
     assert_eq!(output.data(), 1.9964161578120942);
     assert_eq!(x0.grad(), 0.003682978341454416);
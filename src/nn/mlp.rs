@@ -1,15 +1,17 @@
 use rand::Rng;
+use rand::distr::{Distribution, StandardUniform};
 
-use crate::{Layer, Module, Value};
+use crate::{Layer, Module, Scalar, Value};
 
-pub struct MLP {
-  layers: Vec<Layer>,
+pub struct MLP<T: Scalar = f64> {
+  layers: Vec<Layer<T>>,
 }
 
-impl MLP {
+impl<T: Scalar> MLP<T> {
   pub fn new<R>(num_inputs: usize, hidden_layers: &[usize], rng: &mut R) -> Self
   where
     R: Rng,
+    StandardUniform: Distribution<T>,
   {
     let mut sizes = Vec::with_capacity(hidden_layers.len() + 1);
     sizes.push(num_inputs);
@@ -30,8 +32,8 @@ impl MLP {
   }
 }
 
-impl Module for MLP {
-  fn parameters(&self) -> Vec<Value> {
+impl<T: Scalar> Module<T> for MLP<T> {
+  fn parameters(&self) -> Vec<Value<T>> {
     let mut result = vec![];
     for layer in &self.layers {
       result.extend(layer.parameters());
@@ -39,7 +41,7 @@ impl Module for MLP {
     result
   }
 
-  fn call(&self, inputs: &[&Value]) -> Vec<Value> {
+  fn call(&self, inputs: &[&Value<T>]) -> Vec<Value<T>> {
     let mut outputs = inputs.iter().cloned().cloned().collect::<Vec<_>>();
 
     for layer in &self.layers {
@@ -76,7 +78,7 @@ mod tests {
     let mut output = outputs[0].clone();
     output.set_label("output");
 
-    output.backward();
+    output.backward_retain_graph().unwrap();
 
     assert_eq!(output.data(), 0.9743957547369949);
     assert_eq!(x0.grad(), 0.00039792547965581055);
@@ -87,6 +89,24 @@ mod tests {
     std::fs::write("/tmp/micrograd_mlp.svg", output_svg).unwrap();
   }
 
+  #[test]
+  fn test_mlp_no_grad() {
+    let mut rng = StdRng::from_seed([0u8; 32]);
+    let mlp = MLP::new(3, &[4, 4, 1], &mut rng);
+
+    let x0 = Value::new(2.0, Some("x0"));
+    let x1 = Value::new(3.0, Some("x1"));
+    let x2 = Value::new(-1.0, Some("x2"));
+    let inputs = &[&x0, &x1, &x2];
+
+    let expected = mlp.call(inputs)[0].data();
+    let output = crate::no_grad(|| mlp.call(inputs)).remove(0);
+
+    assert_eq!(output.data(), expected);
+    assert!(!output.requires_grad());
+    assert!(output.prev().is_empty());
+  }
+
   #[test]
   fn test_mlp_training() {
     let mut rng = StdRng::from_seed([0u8; 32]);
@@ -126,7 +146,7 @@ mod tests {
         .fold(Value::from(0), |acc, l| acc + l);
 
       mlp.zero_grad();
-      loss.backward();
+      loss.backward().unwrap();
 
       for mut p in mlp.parameters() {
         p.set_data(p.data() - 0.1 * p.grad());
@@ -149,4 +169,51 @@ mod tests {
     assert_eq!(predict(&xs[2]), -0.9829406649047894);
     assert_eq!(predict(&xs[3]), 0.9826812630335173);
   }
+
+  #[test]
+  fn test_mlp_training_f32() {
+    let mut rng = StdRng::from_seed([0u8; 32]);
+    let mut mlp = MLP::<f32>::new(3, &[4, 4, 1], &mut rng);
+
+    let xs = [
+      [2.0, 3.0, -1.0],
+      [3.0, -1.0, 0.5],
+      [0.5, 1.0, 1.0],
+      [1.0, 1.0, -1.0],
+    ]
+    .map(|x| x.map(|v: f32| Value::new(v, None)));
+    let ys = [1.0f32, -1.0, -1.0, 1.0];
+
+    let predict = |mlp: &MLP<f32>, x: &[Value<f32>]| {
+      mlp.call(&x.iter().collect::<Vec<_>>()).remove(0)
+    };
+
+    let mut loss = Value::constant(f32::MAX);
+    for _ in 0..10_000 {
+      let errors = xs
+        .iter()
+        .zip(ys)
+        .map(|(x, y)| {
+          let e = predict(&mlp, x) - y;
+          &e * &e
+        })
+        .collect::<Vec<_>>();
+      loss = Value::sum(&errors);
+
+      mlp.zero_grad();
+      loss.backward().unwrap();
+      for mut p in mlp.parameters() {
+        p.set_data(p.data() - 0.1 * p.grad());
+      }
+
+      if loss.data() < 0.001 {
+        break;
+      }
+    }
+
+    assert!(loss.data() < 0.001);
+    for (x, y) in xs.iter().zip(ys) {
+      assert!((predict(&mlp, x).data() - y).abs() < 0.05);
+    }
+  }
 }
@@ -1,14 +1,18 @@
-use crate::Value;
+use crate::{Scalar, Value};
 pub use layer::*;
 pub use mlp::*;
 pub use neuron::*;
+#[cfg(feature = "parallel")]
+pub use parallel::*;
 
 mod layer;
 mod mlp;
 mod neuron;
+#[cfg(feature = "parallel")]
+mod parallel;
 
-pub trait Module {
-  fn parameters(&self) -> Vec<Value>;
+pub trait Module<T: Scalar = f64> {
+  fn parameters(&self) -> Vec<Value<T>>;
 
   fn zero_grad(&mut self) {
     for mut param in self.parameters() {
@@ -16,5 +20,5 @@ pub trait Module {
     }
   }
 
-  fn call(&self, inputs: &[&Value]) -> Vec<Value>;
+  fn call(&self, inputs: &[&Value<T>]) -> Vec<Value<T>>;
 }
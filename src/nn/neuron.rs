@@ -1,16 +1,18 @@
-use crate::{Module, Value};
+use crate::{Module, Scalar, Value};
 use rand::Rng;
+use rand::distr::{Distribution, StandardUniform};
 
-pub struct Neuron {
-  w: Vec<Value>,
-  b: Value,
+pub struct Neuron<T: Scalar = f64> {
+  w: Vec<Value<T>>,
+  b: Value<T>,
   nonlin: bool,
 }
 
-impl Neuron {
+impl<T: Scalar> Neuron<T> {
   pub fn new<R>(num_input: usize, nonlin: bool, rng: &mut R) -> Self
   where
     R: Rng,
+    StandardUniform: Distribution<T>,
   {
     let mut w = Vec::with_capacity(num_input);
     for i in 0..num_input {
@@ -21,14 +23,14 @@ impl Neuron {
   }
 }
 
-impl Module for Neuron {
-  fn parameters(&self) -> Vec<Value> {
+impl<T: Scalar> Module<T> for Neuron<T> {
+  fn parameters(&self) -> Vec<Value<T>> {
     let mut params = self.w.clone();
     params.push(self.b.clone());
     params
   }
 
-  fn call(&self, inputs: &[&Value]) -> Vec<Value> {
+  fn call(&self, inputs: &[&Value<T>]) -> Vec<Value<T>> {
     assert_eq!(inputs.len(), self.w.len());
     let mut act = self.b.clone();
     for (wi, &xi) in self.w.iter().zip(inputs.iter()) {
@@ -68,7 +70,7 @@ mod tests {
     let mut output = outputs[0].clone();
     output.set_label("output");
 
-    output.backward();
+    output.backward_retain_graph().unwrap();
 
     assert_eq!(output.data(), 0.997985090094488);
     assert_eq!(output.grad(), 1.0);
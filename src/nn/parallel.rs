@@ -0,0 +1,159 @@
+use rayon::prelude::*;
+
+use crate::engine::grad_impl;
+use crate::{Module, Scalar, Value, with_grad};
+
+/// Evaluates `loss` for every example of `batch` on the rayon thread pool and
+/// adds the gradients of the summed loss to the `grad` of the model's
+/// parameters, as if `backward` had been called on the sum. Returns the
+/// summed loss.
+///
+/// Each example builds its own graph, so the threads only share the
+/// parameter nodes, which they read. The per-example gradients are added up
+/// in batch order, so the result doesn't depend on the scheduling.
+///
+/// Hooks on the parameters run once, on the summed gradients, as they would
+/// for a single `backward`.
+///
+/// Every example is evaluated inside [`with_grad`], so the graphs are
+/// recorded even when this is called inside [`crate::no_grad`], including
+/// on a pool thread, which evaluates some of the examples itself.
+pub fn parallel_backward<T, M, X, F>(model: &M, batch: &[X], loss: F) -> T
+where
+  T: Scalar,
+  M: Module<T> + Sync,
+  X: Sync,
+  F: Fn(&M, &X) -> Value<T> + Sync,
+{
+  let params = model.parameters();
+  let results = batch
+    .par_iter()
+    .map(|example| {
+      with_grad(|| {
+        let loss = loss(model, example);
+        (loss.data(), grad_impl(&loss, &params, false))
+      })
+    })
+    .collect::<Vec<_>>();
+
+  let mut total = T::zero();
+  let mut grads = vec![T::zero(); params.len()];
+  for (loss, example_grads) in results {
+    total = total + loss;
+    for (acc, g) in grads.iter_mut().zip(example_grads) {
+      *acc = *acc + g;
+    }
+  }
+
+  for (mut param, g) in params.into_iter().zip(grads) {
+    let g = param.apply_hooks(g);
+    param.add_grad(g);
+  }
+  total
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{MLP, no_grad};
+  use rand::{SeedableRng, rngs::StdRng};
+
+  type Example = ([f64; 3], f64);
+
+  const BATCH: [Example; 4] = [
+    ([2.0, 3.0, -1.0], 1.0),
+    ([3.0, -1.0, 0.5], -1.0),
+    ([0.5, 1.0, 1.0], -1.0),
+    ([1.0, 1.0, -1.0], 1.0),
+  ];
+
+  fn squared_error(mlp: &MLP, (x, y): &Example) -> Value {
+    let x = x.map(Value::constant);
+    let e = mlp.call(&x.iter().collect::<Vec<_>>()).remove(0) - *y;
+    &e * &e
+  }
+
+  #[test]
+  fn test_parallel_backward_matches_backward() {
+    let mlp = MLP::new(3, &[4, 4, 1], &mut StdRng::from_seed([0u8; 32]));
+
+    let mut loss = BATCH
+      .iter()
+      .map(|example| squared_error(&mlp, example))
+      .sum::<Value>();
+    loss.backward().unwrap();
+    let expected = mlp.parameters().iter().map(Value::grad).collect::<Vec<_>>();
+
+    let mut mlp = mlp;
+    mlp.zero_grad();
+    let total = parallel_backward(&mlp, &BATCH, squared_error);
+
+    assert!((total - loss.data()).abs() < 1e-12);
+    for (param, expected) in mlp.parameters().iter().zip(expected) {
+      assert!((param.grad() - expected).abs() < 1e-12);
+    }
+  }
+
+  #[test]
+  fn test_parallel_backward_inside_no_grad() {
+    let mut mlp = MLP::new(3, &[4, 4, 1], &mut StdRng::from_seed([0u8; 32]));
+    parallel_backward(&mlp, &BATCH, squared_error);
+    let expected = mlp.parameters().iter().map(Value::grad).collect::<Vec<_>>();
+
+    // With a single thread, the thread that calls `parallel_backward` under
+    // `no_grad` evaluates every example.
+    mlp.zero_grad();
+    let pool = rayon::ThreadPoolBuilder::new()
+      .num_threads(1)
+      .build()
+      .unwrap();
+    pool.install(|| no_grad(|| parallel_backward(&mlp, &BATCH, squared_error)));
+
+    for (param, expected) in mlp.parameters().iter().zip(expected) {
+      assert_eq!(param.grad(), expected);
+    }
+  }
+
+  #[test]
+  fn test_parallel_backward_runs_hooks() {
+    let mlp = MLP::new(3, &[4, 4, 1], &mut StdRng::from_seed([0u8; 32]));
+    for p in mlp.parameters() {
+      p.register_hook(|g: f64| g.clamp(-0.1, 0.1));
+    }
+
+    let mut loss = BATCH
+      .iter()
+      .map(|example| squared_error(&mlp, example))
+      .sum::<Value>();
+    loss.backward().unwrap();
+    let expected = mlp.parameters().iter().map(Value::grad).collect::<Vec<_>>();
+    assert!(expected.iter().all(|g| g.abs() <= 0.1));
+
+    let mut mlp = mlp;
+    mlp.zero_grad();
+    parallel_backward(&mlp, &BATCH, squared_error);
+
+    for (param, expected) in mlp.parameters().iter().zip(expected) {
+      assert!((param.grad() - expected).abs() < 1e-12);
+    }
+  }
+
+  #[test]
+  fn test_parallel_training() {
+    let mut mlp = MLP::new(3, &[4, 4, 1], &mut StdRng::from_seed([0u8; 32]));
+
+    let mut loss = f64::MAX;
+    for _ in 0..10_000 {
+      mlp.zero_grad();
+      loss = parallel_backward(&mlp, &BATCH, squared_error);
+      for mut p in mlp.parameters() {
+        p.set_data(p.data() - 0.1 * p.grad());
+      }
+      if loss < 0.001 {
+        break;
+      }
+    }
+
+    assert!(loss < 0.001);
+  }
+}